@@ -9,6 +9,7 @@ rustPlatform.buildRustPackage {
   src = ./.;
   cargoLock.lockFile = ./Cargo.lock;
   postInstall = ''
+    ln -s _yfnutool-bin $out/bin/nu_plugin_yfnutool
     mkdir -p $out/share/nushell/vendor/autoload
     cp -r $src/nu-mod/yfnutool $out/share/nushell/vendor/autoload/yfnutool
   '';
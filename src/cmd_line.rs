@@ -3,12 +3,16 @@ use anyhow::{anyhow, Context, Result};
 use bstr::{BStr, BString, ByteSlice};
 #[cfg(test)]
 use pretty_assertions::{assert_eq, assert_ne};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::cmp;
 use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::fmt;
 use std::iter;
 use std::ops::Range;
 use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
 pub trait StrRepr<Src: std::fmt::Display + ?Sized>: ToStrRepr {
     fn str_repr(lit: Src) -> Self;
@@ -28,10 +32,257 @@ impl<T: ToStrRepr> fmt::Debug for Id<&T> {
     }
 }
 
-#[derive(Clone, PartialEq)]
+// Editable text with a cursor, so that transformations can be written once for every backend.
+// Positions are byte offsets unless they say otherwise.
+pub trait Buffer: Clone {
+    fn len(&self) -> usize;
+    fn byte(&self, pos: usize) -> u8;
+    // Only copies if the backend doesn't hold the range contiguously
+    fn slice(&self, range: Range<usize>) -> Cow<'_, [u8]>;
+    // The contiguous text starting at `pos`, for feeding to the parser a piece at a time
+    fn chunk_at(&self, pos: usize) -> &[u8];
+    fn cursor_pos(&self) -> usize;
+    fn set_cursor_pos(&mut self, pos: usize);
+    // Every edit goes through here. The cursor and anchors move as map_pos says.
+    fn replace(&mut self, range: Range<usize>, replacement: &[u8], cursor_gravity: Gravity);
+    fn anchor(&self, name: AnchorName) -> Option<usize>;
+    fn set_anchor(&mut self, name: AnchorName, pos: usize, gravity: Gravity);
+
+    // None if `pos` isn't on a grapheme boundary
+    fn byte_to_grapheme(&self, pos: usize) -> Option<usize> {
+        grapheme_boundaries(&self.contents()).position(|boundary| boundary == pos)
+    }
+    // None if there aren't that many graphemes. One past the last one is the end of the text.
+    fn grapheme_to_byte(&self, grapheme: usize) -> Option<usize> {
+        grapheme_boundaries(&self.contents()).nth(grapheme)
+    }
+    fn is_grapheme_boundary(&self, pos: usize) -> bool {
+        self.byte_to_grapheme(pos).is_some()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn contents(&self) -> Cow<'_, [u8]> {
+        self.slice(0..self.len())
+    }
+
+    fn insert_push_cursor(&mut self, pos: usize, char: u8) {
+        self.replace(pos..pos, &[char], Gravity::Right);
+    }
+    fn insert_no_push_cursor(&mut self, pos: usize, char: u8) {
+        self.replace(pos..pos, &[char], Gravity::Left);
+    }
+    // Drags a cursor sitting just before the deleted character back along with it, like backspace
+    fn delete_pull_cursor(&mut self, pos: usize) {
+        let pulled = self.cursor_pos() == pos;
+        self.replace(pos..pos + 1, b"", Gravity::Left);
+        if pulled {
+            self.set_cursor_pos(self.cursor_pos() - 1);
+        }
+    }
+    fn delete_no_pull_cursor(&mut self, pos: usize) {
+        self.replace(pos..pos + 1, b"", Gravity::Left);
+    }
+    // The cursor gets pushed past text inserted at it
+    fn overwrite_range(&mut self, chars_to_replace: Range<usize>, replacement: &[u8]) {
+        self.replace(chars_to_replace, replacement, Gravity::Right);
+    }
+
+    fn cursor_in(&self, unit: Unit) -> usize {
+        unit.of_byte(self, self.cursor_pos())
+    }
+    // Leaves the cursor alone and fails unless `cursor` lands on a grapheme boundary
+    fn set_cursor_in(&mut self, unit: Unit, cursor: usize) -> Result<()> {
+        let cursor_byte = unit.to_byte(self, cursor).with_context(|| {
+            format!(
+                "Cursor {} ({:?}) does not fall between characters of {:?}",
+                cursor,
+                unit,
+                self.contents().as_bstr()
+            )
+        })?;
+        if !self.is_grapheme_boundary(cursor_byte) {
+            return Err(anyhow!(
+                "Byte index of cursor {} does not fall on a grapheme boundary in {:?}",
+                cursor_byte,
+                self.contents().as_bstr()
+            ));
+        }
+        self.set_cursor_pos(cursor_byte);
+        Ok(())
+    }
+    // Moves a cursor inside a grapheme out of it, saying whether it had to
+    fn snap_cursor(&mut self, snap: Snap) -> bool {
+        let cursor_pos = self.cursor_pos();
+        let snapped_pos = match snap {
+            Snap::Left => (0..cursor_pos + 1)
+                .rev()
+                .find(|pos| self.is_grapheme_boundary(*pos)),
+            Snap::Right => (cursor_pos..self.len() + 1).find(|pos| self.is_grapheme_boundary(*pos)),
+        }
+        .expect("Both ends of the text are grapheme boundaries");
+        self.set_cursor_pos(snapped_pos);
+        snapped_pos != cursor_pos
+    }
+
+    // The line `pos` is on, counting from zero, and where that line starts. Only `\n` ends a line
+    // here, the same as for tree-sitter's rows.
+    fn line_start(&self, pos: usize) -> (usize, usize) {
+        let text = self.slice(0..pos);
+        let line_start = text.rfind_byte(b'\n').map_or(0, |newline| newline + 1);
+        (text[..line_start].find_iter("\n").count(), line_start)
+    }
+
+    // Where `pos` falls, with the column counted in `column` from the start of its line
+    fn point_at(&self, pos: usize, column: Column) -> Point {
+        let (line, line_start) = self.line_start(pos);
+        // The byte after `pos` is enough for first_line to tell whether a `\r` before it ends the
+        // line
+        let text = self.slice(line_start..(pos + 1).min(self.len()));
+        let text = first_line(&text);
+        Point {
+            line,
+            column: column.count(&text[..text.len().min(pos - line_start)]),
+        }
+    }
+    // The position at `point`, if its line exists and the column lands on a grapheme boundary
+    // within it
+    fn pos_at(&self, point: Point, column: Column) -> Option<usize> {
+        let text = self.contents();
+        let mut line_start = 0;
+        for _ in 0..point.line {
+            line_start += text[line_start..].find_byte(b'\n')? + 1;
+        }
+        let pos = line_start + column.to_byte(first_line(&text[line_start..]), point.column)?;
+        Some(pos).filter(|pos| self.is_grapheme_boundary(*pos))
+    }
+    fn cursor_point(&self, column: Column) -> Point {
+        self.point_at(self.cursor_pos(), column)
+    }
+    // Leaves the cursor alone and fails unless `point` lands on a grapheme boundary
+    fn set_cursor_at(&mut self, point: Point, column: Column) -> Result<()> {
+        let pos = self.pos_at(point, column).with_context(|| {
+            format!(
+                "Cursor {} ({:?} columns) does not fall on a grapheme boundary in {:?}",
+                point,
+                column,
+                self.contents().as_bstr()
+            )
+        })?;
+        self.set_cursor_pos(pos);
+        Ok(())
+    }
+}
+
+// Each grapheme of `text` along with where it starts. Every backend splits text this way, the
+// same way nushell's line editor does. An invalid utf-8 sequence is a grapheme of its own, like
+// the replacement character it decodes to.
+fn grapheme_indices(text: &[u8]) -> impl Iterator<Item = (usize, &[u8])> {
+    let mut chunk_start = 0;
+    text.utf8_chunks().flat_map(move |chunk| {
+        let (start, valid, invalid) = (chunk_start, chunk.valid(), chunk.invalid());
+        chunk_start += valid.len() + invalid.len();
+        valid
+            .grapheme_indices(true)
+            .map(move |(idx, grapheme)| (start + idx, grapheme.as_bytes()))
+            .chain((!invalid.is_empty()).then_some((start + valid.len(), invalid)))
+    })
+}
+
+// Where each grapheme of `text` starts, and then where the text ends
+fn grapheme_boundaries(text: &[u8]) -> impl Iterator<Item = usize> + use<'_> {
+    grapheme_indices(text)
+        .map(|(grapheme_start_byte, _)| grapheme_start_byte)
+        .chain(iter::once(text.len()))
+}
+
+// The line `text` starts with, leaving off the `\n` or `\r\n` that ends it
+fn first_line(text: &[u8]) -> &[u8] {
+    match text.find_byte(b'\n') {
+        Some(newline) => text[..newline]
+            .strip_suffix(b"\r")
+            .unwrap_or(&text[..newline]),
+        None => text,
+    }
+}
+
+#[derive(Clone)]
 pub struct Bytes {
     pub text: BString,
     pub cursor_pos: usize,
+    // Every change made through the editing methods below, oldest first
+    pub edits: Vec<Edit>,
+    // Positions other than the cursor that the editing methods keep pointing at the same text
+    pub anchors: BTreeMap<AnchorName, Anchor>,
+}
+// The edits are how we got here rather than where we are, so they don't count
+impl PartialEq for Bytes {
+    fn eq(&self, other: &Self) -> bool {
+        self.text == other.text
+            && self.cursor_pos == other.cursor_pos
+            && self.anchors == other.anchors
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AnchorName {
+    // The end of the selection that isn't the cursor
+    Selection,
+    // Snippet placeholders, in the order they're visited
+    Placeholder(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Anchor {
+    pub pos: usize,
+    pub gravity: Gravity,
+}
+
+// Which side of text inserted exactly at a position that position ends up on. Ordered so that
+// sorting by (pos, gravity) gives an order that no single edit makes positions cross.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Gravity {
+    Left,
+    Right,
+}
+
+// Where `pos` ends up when `range` is replaced by `replacement_len` bytes:
+//   Before the range, or at the start of a non-empty one: unmoved
+//   At or after the end: pushed by the change in length (so at an insertion point, unless it has
+//   Left gravity)
+//   Inside: keeps its offset into the replacement, as far as that goes
+fn map_pos(pos: usize, gravity: Gravity, range: &Range<usize>, replacement_len: usize) -> usize {
+    if pos < range.start || (pos == range.start && (!range.is_empty() || gravity == Gravity::Left))
+    {
+        pos
+    } else if pos >= range.end {
+        pos - range.len() + replacement_len
+    } else {
+        range.start + cmp::min(pos - range.start, replacement_len)
+    }
+}
+
+// Replacing `range` of the text (as it was just before this edit) with `replacement`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Edit {
+    pub range: Range<usize>,
+    pub replacement: BString,
+}
+
+impl Edit {
+    pub fn apply(&self, text: &mut BString) {
+        text.splice(self.range.clone(), self.replacement.iter().copied());
+    }
+}
+
+// `text` after each of `edits` in turn
+pub fn replay(text: &[u8], edits: &[Edit]) -> BString {
+    let mut text = BString::from(text);
+    for edit in edits {
+        edit.apply(&mut text);
+    }
+    text
 }
 impl<T: AsRef<[u8]> + fmt::Display + Sized> StrRepr<T> for Bytes {
     fn str_repr(lit: T) -> Self {
@@ -41,10 +292,7 @@ impl<T: AsRef<[u8]> + fmt::Display + Sized> StrRepr<T> for Bytes {
                 let mut ret = Vec::with_capacity(bytes.len() - 1);
                 ret.extend_from_slice(&bytes[0..idx]);
                 ret.extend_from_slice(&bytes[idx + 1..bytes.len()]);
-                return Bytes {
-                    text: BString::new(ret),
-                    cursor_pos: idx,
-                };
+                return Bytes::new(ret, idx);
             }
         }
         panic!("No '|' in `{}`", &lit);
@@ -70,64 +318,68 @@ impl fmt::Debug for Bytes {
     }
 }
 
-impl Bytes {
-    pub fn insert_push_cursor(&mut self, pos: usize, char: u8) {
-        self.insert_wrt_cursor(pos, char, true);
+impl Buffer for Bytes {
+    fn len(&self) -> usize {
+        self.text.len()
     }
-    pub fn insert_no_push_cursor(&mut self, pos: usize, char: u8) {
-        self.insert_wrt_cursor(pos, char, false);
+    fn byte(&self, pos: usize) -> u8 {
+        self.text[pos]
     }
-    fn insert_wrt_cursor(&mut self, pos: usize, char: u8, push_cursor: bool) {
-        if pos == self.text.len() {
-            self.text.push(char)
-        } else {
-            self.text.insert(pos, char);
-        }
-        let less_than_for_cursor = if push_cursor {
-            cmp::PartialOrd::le
-        } else {
-            cmp::PartialOrd::lt
-        };
-        if less_than_for_cursor(&pos, &self.cursor_pos) {
-            self.cursor_pos += 1;
-        }
+    fn slice(&self, range: Range<usize>) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.text[range])
     }
-    fn delete_pull_cursor(&mut self, pos: usize) {
-        self.delete_wrt_cursor(pos, true);
+    fn chunk_at(&self, pos: usize) -> &[u8] {
+        &self.text[pos..]
     }
-    fn delete_no_pull_cursor(&mut self, pos: usize) {
-        self.delete_wrt_cursor(pos, false);
+    fn cursor_pos(&self) -> usize {
+        self.cursor_pos
     }
-    fn delete_wrt_cursor(&mut self, pos: usize, pull_cursor: bool) {
-        if pos == self.text.len() {
-            self.text.pop();
-        } else {
-            self.text.remove(pos);
-        }
-        let less_than_for_cursor = if pull_cursor {
-            cmp::PartialOrd::le
-        } else {
-            cmp::PartialOrd::lt
-        };
-        if less_than_for_cursor(&pos, &self.cursor_pos) {
-            self.cursor_pos -= 1;
-        }
+    fn set_cursor_pos(&mut self, pos: usize) {
+        self.cursor_pos = pos;
     }
-    // If the cursor is:
-    //   After the characters to be replaced: (>= chars_to_replace.end) it gets pushed by the length of the replacement
-    //   Otherwise: unmoved
-    pub fn overwrite_range(&mut self, chars_to_replace: Range<usize>, replacement: &[u8]) {
-        if chars_to_replace == (self.text.len()..self.text.len()) {
+
+    // Keeps edits and anchors up to date too
+    fn replace(&mut self, range: Range<usize>, replacement: &[u8], cursor_gravity: Gravity) {
+        if range == (self.text.len()..self.text.len()) {
             self.text.extend_from_slice(replacement);
         } else {
-            self.text =
-                Self::overwrite_range_iter(&self.text, &chars_to_replace, replacement).collect();
+            self.text = Self::overwrite_range_iter(&self.text, &range, replacement).collect();
         }
-        if self.cursor_pos >= chars_to_replace.end {
-            self.cursor_pos = self.cursor_pos - chars_to_replace.len() + replacement.len();
+        track_edit(
+            &mut self.edits,
+            &mut self.cursor_pos,
+            &mut self.anchors,
+            range,
+            replacement,
+            cursor_gravity,
+        );
+    }
+    fn anchor(&self, name: AnchorName) -> Option<usize> {
+        self.anchors.get(&name).map(|anchor| anchor.pos)
+    }
+    fn set_anchor(&mut self, name: AnchorName, pos: usize, gravity: Gravity) {
+        self.anchors.insert(name, Anchor { pos, gravity });
+    }
+}
+
+impl Bytes {
+    // No edits recorded yet and no anchors set
+    pub fn new(text: impl Into<BString>, cursor_pos: usize) -> Self {
+        Bytes {
+            text: text.into(),
+            cursor_pos,
+            edits: Vec::new(),
+            anchors: BTreeMap::new(),
         }
     }
 
+    // The cursor is counted in `unit`, and has to fall on a grapheme boundary
+    pub fn with_cursor_in(text: BString, cursor: usize, unit: Unit) -> Result<Self> {
+        let mut bytes = Bytes::new(text, 0);
+        bytes.set_cursor_in(unit, cursor)?;
+        Ok(bytes)
+    }
+
     fn overwrite_range_iter<'a>(
         source: &'a [u8],
         replacement_range: &'a Range<usize>,
@@ -154,6 +406,260 @@ impl Bytes {
     }
 }
 
+// Keeps the log of edits and every position up to date after `range` was replaced
+fn track_edit(
+    edits: &mut Vec<Edit>,
+    cursor_pos: &mut usize,
+    anchors: &mut BTreeMap<AnchorName, Anchor>,
+    range: Range<usize>,
+    replacement: &[u8],
+    cursor_gravity: Gravity,
+) {
+    *cursor_pos = map_pos(*cursor_pos, cursor_gravity, &range, replacement.len());
+    for anchor in anchors.values_mut() {
+        anchor.pos = map_pos(anchor.pos, anchor.gravity, &range, replacement.len());
+    }
+    edits.push(Edit {
+        range,
+        replacement: replacement.into(),
+    });
+}
+
+// The same as Bytes, but edits cost O(log n) rather than O(n), so transformations that make an
+// edit per character stay fast on long command lines. The text has to stay valid utf-8.
+#[derive(Clone)]
+pub struct Rope {
+    pub text: crop::Rope,
+    pub cursor_pos: usize,
+    pub edits: Vec<Edit>,
+    pub anchors: BTreeMap<AnchorName, Anchor>,
+}
+impl PartialEq for Rope {
+    fn eq(&self, other: &Self) -> bool {
+        self.text == other.text
+            && self.cursor_pos == other.cursor_pos
+            && self.anchors == other.anchors
+    }
+}
+impl<T: AsRef<[u8]> + fmt::Display + Sized> StrRepr<T> for Rope {
+    fn str_repr(lit: T) -> Self {
+        Bytes::str_repr(lit)
+            .try_into()
+            .expect("str_repr of a Rope has to be utf-8")
+    }
+}
+impl ToStrRepr for Rope {
+    fn to_str_repr(&self) -> String {
+        Bytes::from(self.clone()).to_str_repr()
+    }
+}
+impl fmt::Debug for Rope {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Id(self).fmt(fmt)
+    }
+}
+
+impl TryFrom<Bytes> for Rope {
+    type Error = anyhow::Error;
+    fn try_from(bytes: Bytes) -> Result<Self> {
+        let text = std::str::from_utf8(&bytes.text).with_context(|| "When converting to Rope")?;
+        Ok(Rope {
+            text: text.into(),
+            cursor_pos: bytes.cursor_pos,
+            edits: bytes.edits,
+            anchors: bytes.anchors,
+        })
+    }
+}
+impl From<Rope> for Bytes {
+    fn from(rope: Rope) -> Self {
+        Bytes {
+            text: rope.text.to_string().into(),
+            cursor_pos: rope.cursor_pos,
+            edits: rope.edits,
+            anchors: rope.anchors,
+        }
+    }
+}
+impl From<Utf8> for Rope {
+    fn from(value: Utf8) -> Self {
+        Bytes::from(value)
+            .try_into()
+            .expect("Utf8 command lines are utf-8")
+    }
+}
+impl TryFrom<Rope> for Utf8 {
+    type Error = anyhow::Error;
+    fn try_from(rope: Rope) -> Result<Self> {
+        Bytes::from(rope).try_into()
+    }
+}
+
+impl Buffer for Rope {
+    fn len(&self) -> usize {
+        self.text.byte_len()
+    }
+    fn byte(&self, pos: usize) -> u8 {
+        self.text.byte(pos)
+    }
+    // Only copies when the range spans more than one chunk of the rope
+    fn slice(&self, range: Range<usize>) -> Cow<'_, [u8]> {
+        let slice = self.text.byte_slice(range);
+        let mut chunks = slice.chunks();
+        match (chunks.next(), chunks.next()) {
+            (None, _) => Cow::Borrowed(b""),
+            (Some(chunk), None) => Cow::Borrowed(chunk.as_bytes()),
+            _ => Cow::Owned(slice.bytes().collect()),
+        }
+    }
+    fn chunk_at(&self, pos: usize) -> &[u8] {
+        if pos >= self.len() {
+            return b"";
+        }
+        self.text
+            .byte_slice(pos..)
+            .chunks()
+            .next()
+            .map_or(b"", str::as_bytes)
+    }
+    // The rope keeps count of its lines, so there's no need to go through everything before `pos`
+    fn line_start(&self, pos: usize) -> (usize, usize) {
+        let line = self.text.line_of_byte(pos);
+        (line, self.text.byte_of_line(line))
+    }
+    fn cursor_pos(&self) -> usize {
+        self.cursor_pos
+    }
+    fn set_cursor_pos(&mut self, pos: usize) {
+        self.cursor_pos = pos;
+    }
+
+    // Panics if the replacement, or the text it leaves, isn't utf-8
+    fn replace(&mut self, range: Range<usize>, replacement: &[u8], cursor_gravity: Gravity) {
+        let replacement_str =
+            std::str::from_utf8(replacement).expect("Rope replacements have to be utf-8");
+        self.text.replace(range.clone(), replacement_str);
+        track_edit(
+            &mut self.edits,
+            &mut self.cursor_pos,
+            &mut self.anchors,
+            range,
+            replacement,
+            cursor_gravity,
+        );
+    }
+    fn anchor(&self, name: AnchorName) -> Option<usize> {
+        self.anchors.get(&name).map(|anchor| anchor.pos)
+    }
+    fn set_anchor(&mut self, name: AnchorName, pos: usize, gravity: Gravity) {
+        self.anchors.insert(name, Anchor { pos, gravity });
+    }
+}
+
+// Edits all made against the same text and applied in one go, so nothing needs to account for
+// how earlier edits moved things around
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Transaction {
+    // Sorted and non-overlapping, in offsets of the text before any of them, each with the side of
+    // it the cursor ends up on if it's inserted right at the cursor
+    edits: Vec<(Edit, Gravity)>,
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // An empty range inserts. Insertions at the same offset end up in the order they were added.
+    pub fn replace(
+        &mut self,
+        range: Range<usize>,
+        replacement: &[u8],
+        cursor_gravity: Gravity,
+    ) -> Result<()> {
+        if range.start > range.end {
+            return Err(anyhow!("Backwards range {:?}", range));
+        }
+        if let Some(edit) = self
+            .edits
+            .iter()
+            .map(|(edit, _)| edit)
+            .find(|edit| edit.range.start < range.end && range.start < edit.range.end)
+        {
+            return Err(anyhow!(
+                "Edit of {:?} overlaps edit of {:?}",
+                range,
+                edit.range
+            ));
+        }
+        let idx = self.edits.partition_point(|(edit, _)| {
+            edit.range.start < range.start
+                || (edit.range.start == range.start && edit.range.is_empty())
+        });
+        self.edits.insert(
+            idx,
+            (
+                Edit {
+                    range,
+                    replacement: replacement.into(),
+                },
+                cursor_gravity,
+            ),
+        );
+        Ok(())
+    }
+    pub fn delete(&mut self, range: Range<usize>) -> Result<()> {
+        self.replace(range, b"", Gravity::Left)
+    }
+
+    // Positions move as they would through the same edits made one at a time
+    pub fn apply(&self, cmd_line: &mut impl Buffer) {
+        for (edit, cursor_gravity) in self.sequential_edits() {
+            cmd_line.replace(edit.range, &edit.replacement, cursor_gravity);
+        }
+    }
+
+    // Undoes this transaction once it's been applied to `original`. The text comes back exactly;
+    // positions only do if they weren't inside a replaced range.
+    pub fn inverse(&self, original: &[u8]) -> Transaction {
+        Transaction {
+            edits: self
+                .edits
+                .iter()
+                .zip(self.sequential_edits())
+                .map(|((edit, cursor_gravity), (sequential, _))| {
+                    (
+                        Edit {
+                            range: sequential.range.start
+                                ..sequential.range.start + edit.replacement.len(),
+                            replacement: original[edit.range.clone()].into(),
+                        },
+                        *cursor_gravity,
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    // Each edit in offsets of the text as the edits before it left it
+    fn sequential_edits(&self) -> impl Iterator<Item = (Edit, Gravity)> + use<'_> {
+        let mut added = 0;
+        let mut removed = 0;
+        self.edits.iter().map(move |(edit, cursor_gravity)| {
+            let start = edit.range.start + added - removed;
+            added += edit.replacement.len();
+            removed += edit.range.len();
+            (
+                Edit {
+                    range: start..start + edit.range.len(),
+                    replacement: edit.replacement.clone(),
+                },
+                *cursor_gravity,
+            )
+        })
+    }
+}
+
 #[cfg(test)]
 mod impl_tests {
     use super::str_repr;
@@ -249,12 +755,394 @@ mod impl_tests {
             pretty_assertions::assert_eq!(actual, expected);
         }
     }
+
+    mod edits {
+        use super::*;
+        use proptest::prelude::*;
+
+        proptest! {
+            #[test]
+            fn should_replay_to_same_text(
+                text in "[a-z]{0,8}",
+                operations in proptest::collection::vec(
+                    (0..3u8, any::<usize>(), any::<usize>(), "[A-Z]{0,3}"),
+                    0..8,
+                ),
+            ) {
+                let mut actual = Bytes::new(text.clone(), 0);
+                for (operation, a, b, replacement) in operations {
+                    let len = actual.text.len();
+                    match operation {
+                        0 => actual.insert_push_cursor(a % (len + 1), b'!'),
+                        1 if len > 0 => actual.delete_no_pull_cursor(a % len),
+                        _ => {
+                            let start = a % (len + 1);
+                            let end = start + b % (len + 1 - start);
+                            actual.overwrite_range(start..end, replacement.as_bytes());
+                        }
+                    }
+                }
+                prop_assert_eq!(replay(text.as_bytes(), &actual.edits), actual.text);
+            }
+        }
+    }
+
+    mod rope {
+        use super::*;
+
+        #[test]
+        fn should_slice_across_chunks() {
+            let text = "ab".repeat(4096);
+            let rope = Rope {
+                text: text.as_str().into(),
+                cursor_pos: 0,
+                edits: Vec::new(),
+                anchors: BTreeMap::new(),
+            };
+            pretty_assertions::assert_eq!(rope.slice(1..8191).as_ref(), &text.as_bytes()[1..8191]);
+            let mut chunks = Vec::new();
+            let mut pos = 0;
+            while pos < rope.len() {
+                let chunk = rope.chunk_at(pos);
+                chunks.extend_from_slice(chunk);
+                pos += chunk.len();
+            }
+            pretty_assertions::assert_eq!(chunks, text.as_bytes());
+        }
+    }
+
+    mod buffer {
+        use super::*;
+        use proptest::prelude::*;
+
+        type Operations = Vec<(u8, usize, usize, String)>;
+
+        fn utf8_and_operations() -> impl Strategy<Value = (Utf8, Operations)> {
+            (
+                "[a-zé🍳]{0,8}",
+                any::<usize>(),
+                proptest::collection::vec(
+                    (0..7u8, any::<usize>(), any::<usize>(), "[A-Zé🍳]{0,3}"),
+                    0..8,
+                ),
+            )
+                .prop_map(|(text, cursor, operations)| {
+                    let cursor_pos_grapheme = cursor % (text.graphemes(true).count() + 1);
+                    (
+                        Utf8 {
+                            text,
+                            cursor_pos_grapheme,
+                            anchors: BTreeMap::new(),
+                        },
+                        operations,
+                    )
+                })
+        }
+
+        fn apply(
+            buffer: &mut impl Buffer,
+            operation: u8,
+            pos: usize,
+            range: Range<usize>,
+            replacement: &[u8],
+        ) {
+            match operation {
+                0 => buffer.insert_push_cursor(pos, b'!'),
+                1 => buffer.insert_no_push_cursor(pos, b'!'),
+                2 => buffer.delete_pull_cursor(pos),
+                3 => buffer.delete_no_pull_cursor(pos),
+                4 => buffer.set_cursor_pos(pos),
+                5 => buffer.replace(range, replacement, Gravity::Left),
+                _ => buffer.overwrite_range(range, replacement),
+            }
+        }
+
+        // Makes the same edits to `buffer` and to Bytes holding the same text, which have to agree
+        // after each one. Edits stay between graphemes, since not every backend can split them.
+        fn check_against_bytes(
+            mut buffer: impl Buffer,
+            operations: Operations,
+        ) -> Result<(), TestCaseError> {
+            let mut bytes = Bytes::new(buffer.contents().into_owned(), buffer.cursor_pos());
+            for (operation, a, b, replacement) in operations {
+                let boundaries: Vec<usize> = (0..bytes.len() + 1)
+                    .filter(|pos| bytes.is_grapheme_boundary(*pos))
+                    .collect();
+                let start = boundaries[a % boundaries.len()];
+                let end = boundaries[b % boundaries.len()];
+                // Deleting a byte, or pulling the cursor back by one, only lands between graphemes
+                // next to ascii
+                let deletable = 0 < start
+                    && start < bytes.len()
+                    && bytes.byte(start).is_ascii()
+                    && bytes.byte(start - 1).is_ascii();
+                if (operation == 2 || operation == 3) && !deletable {
+                    continue;
+                }
+                let range = start.min(end)..start.max(end);
+                apply(
+                    &mut bytes,
+                    operation,
+                    start,
+                    range.clone(),
+                    replacement.as_bytes(),
+                );
+                apply(&mut buffer, operation, start, range, replacement.as_bytes());
+
+                prop_assert_eq!(buffer.contents(), bytes.contents());
+                if bytes.is_grapheme_boundary(bytes.cursor_pos) {
+                    prop_assert_eq!(buffer.cursor_pos(), bytes.cursor_pos);
+                } else {
+                    // A cursor left inside a replaced grapheme either stays put or moves back to
+                    // the start of the grapheme, depending on the backend
+                    let grapheme_start = (0..bytes.cursor_pos)
+                        .rev()
+                        .find(|pos| bytes.is_grapheme_boundary(*pos))
+                        .unwrap();
+                    prop_assert!([bytes.cursor_pos, grapheme_start].contains(&buffer.cursor_pos()));
+                    bytes.cursor_pos = buffer.cursor_pos();
+                }
+                let graphemes = bytes.byte_to_grapheme(bytes.len()).unwrap();
+                for grapheme in 0..graphemes + 2 {
+                    prop_assert_eq!(
+                        buffer.grapheme_to_byte(grapheme),
+                        bytes.grapheme_to_byte(grapheme)
+                    );
+                }
+                for pos in 0..bytes.len() + 1 {
+                    prop_assert_eq!(buffer.byte_to_grapheme(pos), bytes.byte_to_grapheme(pos));
+                }
+                let mut chunks = Vec::new();
+                while chunks.len() < buffer.len() {
+                    chunks.extend_from_slice(buffer.chunk_at(chunks.len()));
+                }
+                prop_assert_eq!(&chunks[..], &bytes.text[..]);
+            }
+            Ok(())
+        }
+
+        proptest! {
+            #[test]
+            fn should_edit_utf8_like_bytes((utf8, operations) in utf8_and_operations()) {
+                check_against_bytes(utf8, operations)?;
+            }
+        }
+        proptest! {
+            #[test]
+            fn should_edit_rope_like_bytes((utf8, operations) in utf8_and_operations()) {
+                check_against_bytes(Rope::from(utf8), operations)?;
+            }
+        }
+
+        #[test]
+        fn should_move_utf8_cursor_back_to_grapheme_start() {
+            let mut utf8: Utf8 = str_repr("a🍳|b");
+            utf8.set_cursor_pos(3);
+            pretty_assertions::assert_eq!(utf8, str_repr("a|🍳b"));
+        }
+    }
+
+    mod anchors {
+        use super::*;
+        use proptest::prelude::*;
+
+        #[parameterized(
+            left_at_insertion = {3, Gravity::Left, 3..3, "ab", 3},
+            right_at_insertion = {3, Gravity::Right, 3..3, "ab", 5},
+            left_at_start_of_replacement = {3, Gravity::Left, 3..5, "ab", 3},
+            right_at_start_of_replacement = {3, Gravity::Right, 3..5, "ab", 3},
+            inside_deletion = {4, Gravity::Right, 3..6, "", 3},
+            inside_replacement = {4, Gravity::Left, 3..6, "abc", 4},
+            at_end_of_replacement = {6, Gravity::Left, 3..6, "a", 4},
+            after = {7, Gravity::Left, 3..6, "abcd", 8},
+        )]
+        fn should_follow_edit(
+            pos: usize,
+            gravity: Gravity,
+            range: Range<usize>,
+            replacement: &str,
+            expected: usize,
+        ) {
+            let mut actual: Bytes = str_repr("|01234567");
+            actual.set_anchor(AnchorName::Selection, pos, gravity);
+            actual.overwrite_range(range, replacement.as_bytes());
+            pretty_assertions::assert_eq!(actual.anchor(AnchorName::Selection), Some(expected));
+        }
+
+        fn cmd_line_with_anchors() -> impl Strategy<Value = Bytes> {
+            "[a-z]{0,8}".prop_flat_map(|text| {
+                let len = text.len();
+                let gravity = prop_oneof![Just(Gravity::Left), Just(Gravity::Right)];
+                (
+                    Just(text),
+                    0..len + 1,
+                    proptest::collection::vec((0..len + 1, gravity), 0..5),
+                )
+                    .prop_map(|(text, cursor_pos, anchors)| {
+                        let mut result = Bytes::new(text, cursor_pos);
+                        for (idx, (pos, gravity)) in anchors.into_iter().enumerate() {
+                            result.set_anchor(AnchorName::Placeholder(idx), pos, gravity);
+                        }
+                        result
+                    })
+            })
+        }
+
+        fn in_order(cmd_line: &Bytes) -> Vec<AnchorName> {
+            let mut names: Vec<_> = cmd_line.anchors.keys().copied().collect();
+            names.sort_by_key(|name| {
+                let anchor = cmd_line.anchors[name];
+                (anchor.pos, anchor.gravity)
+            });
+            names
+        }
+
+        proptest! {
+            #[test]
+            fn should_stay_ordered_and_in_bounds(
+                mut cmd_line in cmd_line_with_anchors(),
+                operations in proptest::collection::vec(
+                    (0..5u8, any::<usize>(), any::<usize>(), "[A-Z]{0,3}"),
+                    0..8,
+                ),
+            ) {
+                for (operation, a, b, replacement) in operations {
+                    // Anchors that end up level can still separate later, but no single edit
+                    // should make any cross
+                    let order = in_order(&cmd_line);
+                    let len = cmd_line.text.len();
+                    match operation {
+                        0 => cmd_line.insert_push_cursor(a % (len + 1), b'!'),
+                        1 => cmd_line.insert_no_push_cursor(a % (len + 1), b'!'),
+                        2 if len > 0 => cmd_line.delete_no_pull_cursor(a % len),
+                        3 if len > 0 && cmd_line.cursor_pos > 0 => {
+                            cmd_line.delete_pull_cursor(a % len)
+                        }
+                        _ => {
+                            let start = a % (len + 1);
+                            let end = start + b % (len + 1 - start);
+                            cmd_line.overwrite_range(start..end, replacement.as_bytes());
+                        }
+                    }
+                    let positions: Vec<usize> =
+                        order.iter().map(|name| cmd_line.anchors[name].pos).collect();
+                    prop_assert!(positions.windows(2).all(|pair| pair[0] <= pair[1]));
+                    prop_assert!(positions.iter().all(|pos| *pos <= cmd_line.text.len()));
+                    prop_assert!(cmd_line.cursor_pos <= cmd_line.text.len());
+                }
+            }
+        }
+    }
+
+    mod transaction {
+        use super::*;
+        use proptest::prelude::*;
+
+        fn transaction(edits: &[(Range<usize>, &str)]) -> Transaction {
+            let mut result = Transaction::new();
+            for (range, replacement) in edits {
+                result
+                    .replace(range.clone(), replacement.as_bytes(), Gravity::Right)
+                    .unwrap();
+            }
+            result
+        }
+
+        #[parameterized(
+            against_original_offsets = {str_repr("echo 'h|i'"), vec![(7..7, "()"), (5..6, "$'")], str_repr("echo $'h()|i'")},
+            cursor_before = {str_repr("|abcd"), vec![(1..3, "X")], str_repr("|aXd")},
+            cursor_at_start_of_replacement = {str_repr("a|bcd"), vec![(1..3, "X")], str_repr("a|Xd")},
+            cursor_inside_replacement = {str_repr("ab|cd"), vec![(1..4, "X")], str_repr("aX|")},
+            cursor_inside_longer_replacement = {str_repr("ab|cd"), vec![(1..3, "XYZ")], str_repr("aX|YZd")},
+            cursor_at_end_of_replacement = {str_repr("abc|d"), vec![(1..3, "XYZ")], str_repr("aXYZ|d")},
+            insertions_in_order = {str_repr("x|y"), vec![(1..1, "a"), (1..1, "b")], str_repr("xab|y")},
+            insertion_before_replacement = {str_repr("|xy"), vec![(1..2, "Y"), (1..1, "a")], str_repr("|xaY")},
+        )]
+        fn should_apply(before: Rope, edits: Vec<(Range<usize>, &str)>, expected: Rope) {
+            let mut actual = before.clone();
+            transaction(&edits).apply(&mut actual);
+            pretty_assertions::assert_eq!(actual, expected);
+            pretty_assertions::assert_eq!(
+                replay(before.text.to_string().as_bytes(), &actual.edits),
+                actual.text.to_string()
+            );
+        }
+
+        #[parameterized(
+            overlapping = {vec![(1..3, "a"), (2..4, "b")]},
+            insertion_inside = {vec![(1..3, "a"), (2..2, "b")]},
+            backwards = {vec![(Range { start: 3, end: 1 }, "a")]},
+        )]
+        fn should_reject(edits: Vec<(Range<usize>, &str)>) {
+            let (last, rest) = edits.split_last().unwrap();
+            let mut result = transaction(rest);
+            assert!(result
+                .replace(last.0.clone(), last.1.as_bytes(), Gravity::Right)
+                .is_err());
+        }
+
+        #[test]
+        fn should_leave_cursor_before_left_insertion() {
+            let mut actual = str_repr::<_, Rope>("'a|'");
+            let mut transaction = Transaction::new();
+            transaction.replace(2..2, b"(", Gravity::Right).unwrap();
+            transaction.replace(2..2, b")", Gravity::Left).unwrap();
+            transaction.apply(&mut actual);
+            pretty_assertions::assert_eq!(actual, str_repr("'a(|)'"));
+        }
+
+        fn text_and_transaction() -> impl Strategy<Value = (String, Transaction)> {
+            "[a-z]{0,8}".prop_flat_map(|text| {
+                let len = text.len();
+                let edits = proptest::collection::vec((0..len + 1, 0..len + 1, "[A-Z]{0,3}"), 0..5);
+                (Just(text), edits).prop_map(|(text, edits)| {
+                    let mut transaction = Transaction::new();
+                    for (a, b, replacement) in edits {
+                        // Whatever overlaps is just left out
+                        let _ = transaction.replace(
+                            a.min(b)..a.max(b),
+                            replacement.as_bytes(),
+                            Gravity::Right,
+                        );
+                    }
+                    (text, transaction)
+                })
+            })
+        }
+
+        proptest! {
+            #[test]
+            fn should_undo_with_inverse((text, transaction) in text_and_transaction()) {
+                let mut cmd_line = Rope {
+                    text: text.as_str().into(),
+                    cursor_pos: 0,
+                    edits: Vec::new(),
+                    anchors: BTreeMap::new(),
+                };
+                transaction.apply(&mut cmd_line);
+                transaction.inverse(text.as_bytes()).apply(&mut cmd_line);
+                prop_assert_eq!(cmd_line.text.to_string(), text);
+            }
+
+            #[test]
+            fn should_match_editing_from_the_end((text, transaction) in text_and_transaction()) {
+                let mut expected = Bytes::new(text.clone(), 0);
+                let mut actual = Rope::try_from(expected.clone()).unwrap();
+                for (edit, _) in transaction.edits.iter().rev() {
+                    expected.overwrite_range(edit.range.clone(), &edit.replacement);
+                }
+                transaction.apply(&mut actual);
+                prop_assert_eq!(BString::from(actual.text.to_string()), expected.text);
+            }
+        }
+    }
 }
 
 #[derive(Clone, PartialEq)]
 pub struct Utf8 {
     pub text: String,
     pub cursor_pos_grapheme: usize,
+    pub anchors: BTreeMap<AnchorName, Anchor>,
 }
 
 impl TryFrom<Bytes> for Utf8 {
@@ -262,19 +1150,16 @@ impl TryFrom<Bytes> for Utf8 {
     fn try_from(bytes: Bytes) -> Result<Self> {
         if bytes.cursor_pos == bytes.text.len() {
             return Ok(Utf8 {
-                cursor_pos_grapheme: bytes.text.graphemes().count(),
+                cursor_pos_grapheme: grapheme_indices(&bytes.text).count(),
                 text: bytes
                     .text
                     .try_into()
                     .with_context(|| "When converting to Utf8 Command line")?,
+                anchors: bytes.anchors,
             });
         }
-        let pos_result = bytes
-            .text
-            .grapheme_indices()
-            .enumerate()
-            .find(|(_idx, (grapheme_start_byte, _, _))| *grapheme_start_byte == bytes.cursor_pos)
-            .map(|(cursor_pos_grapheme_idx, _)| cursor_pos_grapheme_idx);
+        let pos_result = grapheme_indices(&bytes.text)
+            .position(|(grapheme_start_byte, _)| grapheme_start_byte == bytes.cursor_pos);
         let text = bytes
             .text
             .try_into()
@@ -290,9 +1175,37 @@ impl TryFrom<Bytes> for Utf8 {
         Ok(Utf8 {
             text,
             cursor_pos_grapheme,
+            anchors: bytes.anchors,
         })
     }
 }
+// Which way a cursor inside a grapheme moves to get out of it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Snap {
+    // To the start of the grapheme
+    #[default]
+    Left,
+    // To the start of the next one
+    Right,
+}
+
+impl Utf8 {
+    // Like the TryFrom conversion, except that a cursor inside a grapheme moves out of it rather
+    // than failing. Also says whether it had to move.
+    pub fn snapped_from(mut bytes: Bytes, snap: Snap) -> Result<(Self, bool)> {
+        if bytes.cursor_pos > bytes.text.len() {
+            return Err(anyhow!(
+                "Byte index of cursor {} is past the end of {:?}",
+                bytes.cursor_pos,
+                bytes.text
+            ));
+        }
+        let snapped = bytes.snap_cursor(snap);
+        Ok((bytes.try_into()?, snapped))
+    }
+}
+
 impl From<Utf8> for Bytes {
     fn from(value: Utf8) -> Self {
         let cursor_pos = match value
@@ -309,6 +1222,8 @@ impl From<Utf8> for Bytes {
         Bytes {
             cursor_pos,
             text: value.text.into(),
+            edits: Vec::new(),
+            anchors: value.anchors,
         }
     }
 }
@@ -349,6 +1264,7 @@ impl<T: AsRef<str> + fmt::Display + Sized> StrRepr<T> for Utf8 {
         Utf8 {
             cursor_pos_grapheme: result_idx.unwrap(),
             text: result_text,
+            anchors: BTreeMap::new(),
         }
     }
 }
@@ -358,7 +1274,174 @@ impl fmt::Debug for Utf8 {
     }
 }
 
-impl Utf8 {}
+// Ways of counting a position in text, for talking to callers that don't count in graphemes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Unit {
+    // What nushell's `commandline get-cursor` counts
+    #[default]
+    Grapheme,
+    // Utf-8 bytes
+    Byte,
+    // Unicode scalar values
+    Char,
+    // Utf-16 code units, as javascript and the language server protocol count
+    Utf16,
+}
+
+impl Unit {
+    // Byte offset of `pos`, counted in this unit, if it falls between characters of `buffer`.
+    // Invalid utf-8 is counted in characters the way bstr decodes it.
+    fn to_byte(self, buffer: &impl Buffer, pos: usize) -> Option<usize> {
+        let text = buffer.contents();
+        let mut char_starts = text
+            .char_indices()
+            .map(|(start, _, _)| start)
+            .chain(iter::once(text.len()));
+        match self {
+            Unit::Grapheme => buffer.grapheme_to_byte(pos),
+            Unit::Byte => char_starts.find(|start| *start == pos),
+            Unit::Char => char_starts.nth(pos),
+            Unit::Utf16 => char_starts
+                .find(|start| utf16_len(&text[..*start]) >= pos)
+                .filter(|start| utf16_len(&text[..*start]) == pos),
+        }
+    }
+
+    // `byte` counted in this unit. It has to fall between characters of `buffer`, and counts as
+    // the start of its grapheme if it's inside one.
+    fn of_byte(self, buffer: &impl Buffer, byte: usize) -> usize {
+        let text = buffer.contents();
+        match self {
+            Unit::Grapheme => (0..byte + 1)
+                .rev()
+                .find_map(|pos| buffer.byte_to_grapheme(pos))
+                .expect("The start of the text is a grapheme boundary"),
+            Unit::Byte => byte,
+            Unit::Char => text[..byte].chars().count(),
+            Unit::Utf16 => utf16_len(&text[..byte]),
+        }
+    }
+}
+
+fn utf16_len(text: &[u8]) -> usize {
+    text.chars().map(char::len_utf16).sum()
+}
+
+// A position as a line and a column within it, both counted from zero. Lines end at `\n`, and a
+// `\r` just before one belongs to the line ending rather than to the line.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Point {
+    pub line: usize,
+    pub column: usize,
+}
+
+// Numbered from one, the way editors show them
+impl fmt::Display for Point {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "{}:{}", self.line + 1, self.column + 1)
+    }
+}
+
+// Ways of counting columns within a line
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Column {
+    #[default]
+    Grapheme,
+    // Utf-8 bytes, as tree-sitter counts
+    Byte,
+    // Terminal cells, with wide characters taking two and tabs just one
+    Width,
+}
+
+impl Column {
+    fn count(self, line: &[u8]) -> usize {
+        match self {
+            Column::Grapheme => grapheme_indices(line).count(),
+            Column::Byte => line.len(),
+            // The replacement character for an invalid sequence takes one cell
+            Column::Width => grapheme_indices(line)
+                .map(|(_, grapheme)| grapheme.to_str().map_or(1, UnicodeWidthStr::width))
+                .sum(),
+        }
+    }
+
+    // Byte offset within `line` of the start of `column`, if one starts there
+    fn to_byte(self, line: &[u8], column: usize) -> Option<usize> {
+        let mut grapheme_starts = grapheme_boundaries(line);
+        match self {
+            Column::Grapheme => grapheme_starts.nth(column),
+            Column::Byte => Some(column).filter(|column| *column <= line.len()),
+            Column::Width => grapheme_starts
+                .find(|start| self.count(&line[..*start]) >= column)
+                .filter(|start| self.count(&line[..*start]) == column),
+        }
+    }
+}
+
+impl Utf8 {
+    // The cursor is counted in `unit`, and has to fall on a grapheme boundary
+    pub fn with_cursor_in(text: String, cursor: usize, unit: Unit) -> Result<Self> {
+        let mut utf8 = Utf8 {
+            text,
+            cursor_pos_grapheme: 0,
+            anchors: BTreeMap::new(),
+        };
+        utf8.set_cursor_in(unit, cursor)?;
+        Ok(utf8)
+    }
+}
+
+// Utf8 only has room for cursors on grapheme boundaries, so a cursor set anywhere else moves back
+// to the start of its grapheme. There's no record of edits either.
+impl Buffer for Utf8 {
+    fn len(&self) -> usize {
+        self.text.len()
+    }
+    fn byte(&self, pos: usize) -> u8 {
+        self.text.as_bytes()[pos]
+    }
+    fn slice(&self, range: Range<usize>) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.text.as_bytes()[range])
+    }
+    fn chunk_at(&self, pos: usize) -> &[u8] {
+        &self.text.as_bytes()[pos..]
+    }
+    fn cursor_pos(&self) -> usize {
+        // As in the conversion to Bytes, a cursor past the last grapheme is at the end
+        self.grapheme_to_byte(self.cursor_pos_grapheme)
+            .unwrap_or(self.text.len())
+    }
+    fn set_cursor_pos(&mut self, pos: usize) {
+        self.cursor_pos_grapheme = self
+            .text
+            .grapheme_indices(true)
+            .take_while(|(grapheme_start_byte, grapheme)| {
+                grapheme_start_byte + grapheme.len() <= pos
+            })
+            .count();
+    }
+
+    // Panics if the replacement, or the text it leaves, isn't utf-8
+    fn replace(&mut self, range: Range<usize>, replacement: &[u8], cursor_gravity: Gravity) {
+        let cursor_pos = map_pos(self.cursor_pos(), cursor_gravity, &range, replacement.len());
+        for anchor in self.anchors.values_mut() {
+            anchor.pos = map_pos(anchor.pos, anchor.gravity, &range, replacement.len());
+        }
+        self.text.replace_range(
+            range,
+            std::str::from_utf8(replacement).expect("Utf8 replacements have to be utf-8"),
+        );
+        self.set_cursor_pos(cursor_pos);
+    }
+    fn anchor(&self, name: AnchorName) -> Option<usize> {
+        self.anchors.get(&name).map(|anchor| anchor.pos)
+    }
+    fn set_anchor(&mut self, name: AnchorName, pos: usize, gravity: Gravity) {
+        self.anchors.insert(name, Anchor { pos, gravity });
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -373,10 +1456,10 @@ mod tests {
             fn roundtrip_bytes(
                 (text, cursor_pos) in "[^|]*".prop_flat_map(|str| {
                     let bytes = str.as_bytes();
-                    (Just(bytes.to_owned().into()), 0..(bytes.len() + 1))
+                    (Just(bytes.to_owned()), 0..(bytes.len() + 1))
                 })
             ) {
-                let original = Bytes { cursor_pos, text };
+                let original = Bytes::new(text, cursor_pos);
                 let repr = original.to_str_repr();
                 prop_assume!(repr.chars().find(|chr| { *chr == std::char::REPLACEMENT_CHARACTER }).is_none());
                 prop_assert_eq!(original, str_repr(repr));
@@ -390,7 +1473,7 @@ mod tests {
                     (Just(str.to_string()), 0..(graphemes+1))
                 })
             ) {
-                let original = Utf8 { cursor_pos_grapheme, text };
+                let original = Utf8 { cursor_pos_grapheme, text, anchors: BTreeMap::new() };
                 let repr = original.to_str_repr();
                 prop_assert_eq!(original, str_repr(repr));
             }
@@ -404,9 +1487,210 @@ mod tests {
                 (Just(str.to_string()), 0..(graphemes+1))
             })
         ) {
-            let original = Utf8 { cursor_pos_grapheme, text };
+            let original = Utf8 { cursor_pos_grapheme, text, anchors: BTreeMap::new() };
             prop_assert_eq!(original.clone(), <Utf8 as Into<Bytes>>::into(original).try_into().unwrap());
         }
 
     }
+
+    mod snap {
+        use super::*;
+        use yare::parameterized;
+
+        #[parameterized(
+            left = {Snap::Left, "a|🍳b", true},
+            right = {Snap::Right, "a🍳|b", true},
+        )]
+        fn should_snap_out_of_grapheme(snap: Snap, expected: &str, snapped: bool) {
+            let bytes = Bytes::new("a🍳b", 2);
+            pretty_assertions::assert_eq!(
+                Utf8::snapped_from(bytes, snap).unwrap(),
+                (str_repr(expected), snapped)
+            );
+        }
+
+        #[parameterized(
+            left = {Snap::Left},
+            right = {Snap::Right},
+        )]
+        fn should_leave_cursor_on_boundary(snap: Snap) {
+            pretty_assertions::assert_eq!(
+                Utf8::snapped_from(str_repr("a🍳|b"), snap).unwrap(),
+                (str_repr("a🍳|b"), false)
+            );
+        }
+
+        proptest! {
+            #[test]
+            fn any_byte_cursor_snaps(
+                snap in prop_oneof![Just(Snap::Left), Just(Snap::Right)],
+                (text, cursor_pos) in any::<String>().prop_flat_map(|str| {
+                    let len = str.len();
+                    (Just(str), 0..(len + 1))
+                })
+            ) {
+                let bytes = Bytes::new(text, cursor_pos);
+                let (utf8, snapped) = Utf8::snapped_from(bytes, snap).unwrap();
+                let snapped_pos = utf8.cursor_pos();
+                prop_assert_eq!(snapped, snapped_pos != cursor_pos);
+                match snap {
+                    Snap::Left => prop_assert!(snapped_pos <= cursor_pos),
+                    Snap::Right => prop_assert!(snapped_pos >= cursor_pos),
+                }
+                // To the nearest boundary that way
+                let between = snapped_pos.min(cursor_pos) + 1..snapped_pos.max(cursor_pos);
+                prop_assert!(between.into_iter().all(|pos| !utf8.is_grapheme_boundary(pos)));
+            }
+        }
+    }
+
+    mod unit {
+        use super::*;
+        use yare::parameterized;
+
+        fn any_unit() -> impl Strategy<Value = Unit> {
+            prop_oneof![
+                Just(Unit::Grapheme),
+                Just(Unit::Byte),
+                Just(Unit::Char),
+                Just(Unit::Utf16),
+            ]
+        }
+
+        proptest! {
+            #[test]
+            fn roundtrip_cursor(
+                unit in any_unit(),
+                (text, cursor_pos_grapheme) in any::<String>().prop_flat_map(|str| {
+                    let graphemes = str.graphemes(true).count();
+                    (Just(str), 0..(graphemes+1))
+                })
+            ) {
+                let original = Utf8 { cursor_pos_grapheme, text, anchors: BTreeMap::new() };
+                let cursor = original.cursor_in(unit);
+                prop_assert_eq!(Utf8::with_cursor_in(original.text.clone(), cursor, unit).unwrap(), original);
+            }
+        }
+        proptest! {
+            #[test]
+            fn roundtrip_char_boundaries(unit in any_unit(), text in any::<String>()) {
+                prop_assume!(unit != Unit::Grapheme);
+                let buffer = Utf8 { text: text.clone(), cursor_pos_grapheme: 0, anchors: BTreeMap::new() };
+                for (idx, _) in text.char_indices().chain(iter::once((text.len(), ' '))) {
+                    prop_assert_eq!(unit.to_byte(&buffer, unit.of_byte(&buffer, idx)), Some(idx));
+                }
+            }
+        }
+
+        #[parameterized(
+            grapheme = {Unit::Grapheme, 2},
+            byte = {Unit::Byte, 7},
+            char = {Unit::Char, 3},
+            utf16 = {Unit::Utf16, 4},
+        )]
+        fn should_count_cursor_in(unit: Unit, expected: usize) {
+            let utf8: Utf8 = str_repr("🍳e\u{301}|b");
+            pretty_assertions::assert_eq!(utf8.cursor_in(unit), expected);
+            pretty_assertions::assert_eq!(
+                Utf8::with_cursor_in(utf8.text.clone(), expected, unit).unwrap(),
+                utf8
+            );
+        }
+
+        #[parameterized(
+            inside_char = {Unit::Byte, 1},
+            inside_surrogate_pair = {Unit::Utf16, 1},
+            inside_grapheme = {Unit::Char, 2},
+            past_end = {Unit::Grapheme, 4},
+        )]
+        fn should_reject_cursor(unit: Unit, cursor: usize) {
+            assert!(Utf8::with_cursor_in("🍳e\u{301}b".to_string(), cursor, unit).is_err());
+        }
+
+        // Invalid sequences count as the one replacement character bstr decodes them to
+        #[parameterized(
+            grapheme = {Unit::Grapheme, 3},
+            byte = {Unit::Byte, 6},
+            char = {Unit::Char, 4},
+            utf16 = {Unit::Utf16, 4},
+        )]
+        fn should_count_cursor_in_invalid_utf8(unit: Unit, expected: usize) {
+            let text = BString::from(b"\xffe\xcc\x81\xf0\x9f" as &[u8]);
+            let bytes = Bytes::with_cursor_in(text, expected, unit).unwrap();
+            pretty_assertions::assert_eq!(bytes.cursor_pos(), 6);
+            pretty_assertions::assert_eq!(bytes.cursor_in(unit), expected);
+        }
+
+        #[parameterized(
+            left = {Snap::Left, 1},
+            right = {Snap::Right, 4},
+        )]
+        fn should_snap_cursor_in_invalid_utf8(snap: Snap, snapped_pos: usize) {
+            let mut bytes = Bytes::with_cursor_in(b"\xffe\xcc\x81".into(), 0, Unit::Byte).unwrap();
+            bytes.set_cursor_pos(2);
+            assert!(bytes.snap_cursor(snap));
+            pretty_assertions::assert_eq!(bytes.cursor_pos(), snapped_pos);
+        }
+    }
+
+    mod point {
+        use super::*;
+        use yare::parameterized;
+
+        fn any_column() -> impl Strategy<Value = Column> {
+            prop_oneof![
+                Just(Column::Grapheme),
+                Just(Column::Byte),
+                Just(Column::Width)
+            ]
+        }
+
+        proptest! {
+            #[test]
+            fn roundtrip_grapheme_boundaries(column in any_column(), text in "[a\r\n\té🍳]{0,12}") {
+                let bytes: Bytes = str_repr(text.clone() + "|");
+                for pos in (0..text.len() + 1).filter(|pos| bytes.is_grapheme_boundary(*pos)) {
+                    let point = bytes.point_at(pos, column);
+                    prop_assert_eq!(bytes.pos_at(point, column), Some(pos), "{}", point);
+                }
+            }
+        }
+
+        #[parameterized(
+            first_line = {"ab|c\nd", Column::Grapheme, Point { line: 0, column: 2 }},
+            after_newline = {"abc\n|d", Column::Grapheme, Point { line: 1, column: 0 }},
+            after_crlf = {"abc\r\nd|", Column::Grapheme, Point { line: 1, column: 1 }},
+            end_of_crlf_line = {"abc|\r\nd", Column::Grapheme, Point { line: 0, column: 3 }},
+            graphemes = {"x\n🍳e\u{301}|b", Column::Grapheme, Point { line: 1, column: 2 }},
+            bytes = {"x\n🍳e\u{301}|b", Column::Byte, Point { line: 1, column: 7 }},
+            width = {"x\n🍳e\u{301}|b", Column::Width, Point { line: 1, column: 3 }},
+        )]
+        fn should_find_point(before: &str, column: Column, point: Point) {
+            let bytes: Bytes = str_repr(before);
+            pretty_assertions::assert_eq!(bytes.cursor_point(column), point);
+            let mut moved = Bytes {
+                cursor_pos: 0,
+                ..bytes.clone()
+            };
+            moved.set_cursor_at(point, column).unwrap();
+            pretty_assertions::assert_eq!(moved, bytes);
+        }
+
+        #[parameterized(
+            past_end_of_line = {Point { line: 0, column: 4 }, Column::Grapheme},
+            inside_crlf = {Point { line: 0, column: 4 }, Column::Byte},
+            missing_line = {Point { line: 2, column: 0 }, Column::Grapheme},
+            inside_wide_char = {Point { line: 1, column: 1 }, Column::Width},
+            inside_grapheme = {Point { line: 1, column: 5 }, Column::Byte},
+        )]
+        fn should_reject_point(point: Point, column: Column) {
+            let bytes: Bytes = str_repr("abc\r\n🍳e\u{301}|");
+            pretty_assertions::assert_eq!(bytes.pos_at(point, column), None);
+        }
+
+        #[test]
+        fn should_show_point_from_one() {
+            pretty_assertions::assert_eq!(Point { line: 0, column: 4 }.to_string(), "1:5");
+        }
+    }
 }
@@ -0,0 +1,302 @@
+use crate::cmd_line::{self, Buffer};
+use crate::escape::Quote;
+use crate::syntax::Recovered;
+use anyhow::{Context, Result};
+use tree_sitter::Node;
+use yfnutool_macros::nu_kind_sym;
+
+// What the cursor is in, for operations to decide what to act on without each walking the tree
+// their own way
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CursorContext<'tree> {
+    // The innermost named node at the cursor
+    pub node: Node<'tree>,
+    pub string: Option<StringAt<'tree>>,
+    pub role: Role<'tree>,
+    pub pipe_element: Option<Node<'tree>>,
+    // The pipeline (or comment) that makes up one whole entry of the script, closure, block or
+    // subexpression the cursor is in
+    pub statement: Option<Node<'tree>>,
+}
+
+// The string literal the cursor is in. Unfinished quoted strings that no closer could make parse
+// show up as an ERROR starting with the quote.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StringAt<'tree> {
+    pub quote: Quote,
+    pub node: Node<'tree>,
+}
+
+// The part the innermost interesting node at the cursor plays
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role<'tree> {
+    CommandName {
+        command: Node<'tree>,
+    },
+    Flag(Node<'tree>),
+    // Counting positional arguments only, so flags and the command name don't shift the index
+    Argument {
+        index: usize,
+        argument: Node<'tree>,
+        command: Node<'tree>,
+    },
+    // The parameters of a closure or a def
+    ParameterList(Node<'tree>),
+    CellPath(Node<'tree>),
+    Comment(Node<'tree>),
+    RecordKey(Node<'tree>),
+    Other,
+}
+
+// A cursor off the end of the buffer counts as being on the last byte, and one in an empty buffer
+// as being on the root
+pub fn classify<'tree>(
+    recovered: &'tree Recovered,
+    input: &impl Buffer,
+) -> Result<CursorContext<'tree>> {
+    let effective_cursor_pos = if input.cursor_pos() == input.len() {
+        input.cursor_pos().saturating_sub(1)
+    } else {
+        input.cursor_pos()
+    };
+    let node = recovered
+        .tree
+        .root_node()
+        .named_descendant_for_byte_range(effective_cursor_pos, effective_cursor_pos)
+        .with_context(|| {
+            format!(
+                "Unable to find node at cursor position {} ({})",
+                effective_cursor_pos,
+                input.point_at(effective_cursor_pos, cmd_line::Column::Grapheme)
+            )
+        })?;
+
+    Ok(CursorContext {
+        node,
+        string: string_at(recovered, input, node),
+        role: role(node),
+        pipe_element: ancestors(node).find(|node| is_pipe_element(*node)),
+        statement: ancestors(node).find(|node| is_statement(*node)),
+    })
+}
+
+// `node` and then each of its parents in turn
+pub fn ancestors(node: Node) -> impl Iterator<Item = Node> {
+    std::iter::successors(Some(node), Node::parent)
+}
+
+// `node` and everything under it, each before its children
+pub fn descendants(node: Node) -> impl Iterator<Item = Node> {
+    let mut cursor = node.walk();
+    let mut done = false;
+    std::iter::from_fn(move || {
+        if done {
+            return None;
+        }
+        let node = cursor.node();
+        if !cursor.goto_first_child() && !cursor.goto_next_sibling() {
+            done = loop {
+                if !cursor.goto_parent() {
+                    break true;
+                }
+                if cursor.goto_next_sibling() {
+                    break false;
+                }
+            };
+        }
+        Some(node)
+    })
+}
+
+pub fn is_statement(node: Node) -> bool {
+    node.is_named()
+        && node.kind_id() != nu_kind_sym!("parameter_pipes")
+        && node.parent().is_some_and(|parent| {
+            matches!(
+                parent.kind_id(),
+                nu_kind_sym!("nu_script")
+                    | nu_kind_sym!("block")
+                    | nu_kind_sym!("val_closure")
+                    | nu_kind_sym!("expr_parenthesized")
+            )
+        })
+}
+
+pub fn is_pipe_element(node: Node) -> bool {
+    node.kind().starts_with("pipe_element")
+}
+
+fn string_at<'tree>(
+    recovered: &Recovered,
+    input: &impl Buffer,
+    node: Node<'tree>,
+) -> Option<StringAt<'tree>> {
+    if node.kind_id() == nu_kind_sym!("ERROR") {
+        let quote = Quote::at_start(&input.slice(recovered.range(node)))?;
+        return Some(StringAt { quote, node });
+    }
+    let node = ancestors(node).find(|node| {
+        matches!(
+            node.kind_id(),
+            nu_kind_sym!("val_string") | nu_kind_sym!("val_interpolated")
+        )
+    })?;
+    let quote = Quote::at_start(&input.slice(recovered.range(node))).unwrap_or(Quote::Bare);
+    Some(StringAt { quote, node })
+}
+
+fn role(node: Node) -> Role {
+    for node in ancestors(node) {
+        match node.kind_id() {
+            nu_kind_sym!("comment") => return Role::Comment(node),
+            nu_kind_sym!("parameter_pipes") | nu_kind_sym!("parameter_bracks") => {
+                return Role::ParameterList(node)
+            }
+            nu_kind_sym!("cell_path") => return Role::CellPath(node),
+            nu_kind_sym!("long_flag") | nu_kind_sym!("short_flag") => return Role::Flag(node),
+            _ => (),
+        }
+        let Some(parent) = node.parent() else {
+            break;
+        };
+        match parent.kind_id() {
+            nu_kind_sym!("command") if node.kind_id() == nu_kind_sym!("cmd_identifier") => {
+                return Role::CommandName { command: parent };
+            }
+            nu_kind_sym!("command") => {
+                let mut cursor = parent.walk();
+                let index = parent
+                    .named_children(&mut cursor)
+                    .take_while(|child| *child != node)
+                    .filter(|child| is_positional(*child))
+                    .count();
+                return Role::Argument {
+                    index,
+                    argument: node,
+                    command: parent,
+                };
+            }
+            nu_kind_sym!("record_entry") if parent.child_by_field_name("key") == Some(node) => {
+                return Role::RecordKey(node);
+            }
+            _ => (),
+        }
+    }
+    Role::Other
+}
+
+fn is_positional(node: Node) -> bool {
+    !matches!(
+        node.kind_id(),
+        nu_kind_sym!("cmd_identifier")
+            | nu_kind_sym!("long_flag")
+            | nu_kind_sym!("short_flag")
+            | nu_kind_sym!("comment")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd_line::{Bytes, Unit};
+    use crate::syntax::Parsed;
+    use bstr::ByteSlice;
+    use yare::parameterized;
+
+    // Runs `check` against the context at byte `cursor`, along with a way to show a node's text
+    fn with_context<T>(
+        text: &str,
+        cursor: usize,
+        check: impl FnOnce(CursorContext, &dyn Fn(Node) -> String) -> T,
+    ) -> T {
+        let mut parser = crate::nu_parser().unwrap();
+        let mut input =
+            Parsed::new(Bytes::with_cursor_in(text.into(), cursor, Unit::Byte).unwrap());
+        let recovered = input.recovered_tree(&mut parser).unwrap();
+        let context = classify(&recovered, &input).unwrap();
+        check(context, &|node| {
+            input
+                .slice(recovered.range(node))
+                .to_str_lossy()
+                .into_owned()
+        })
+    }
+
+    #[parameterized(
+        command_name = {"echo foo", 2, "command name of `echo foo`"},
+        first_argument = {"echo foo bar", 6, "argument 0 `foo`"},
+        argument_after_flag = {"ls --all foo bar", 14, "argument 1 `bar`"},
+        end_of_argument = {"cp foo bar", 10, "argument 1 `bar`"},
+        long_flag = {"ls --all", 5, "flag `--all`"},
+        short_flag = {"ls -a", 4, "flag `-a`"},
+        closure_parameters = {"each {|x, y| $x }", 8, "parameters `|x, y|`"},
+        def_parameters = {"def foo [bar: int] {}", 10, "parameters `[bar: int]`"},
+        cell_path = {"$foo.bar.baz", 6, "cell path `.bar.baz`"},
+        comment = {"ls # list files", 7, "comment `# list files`"},
+        record_key = {"{foo: 1}", 2, "record key `foo`"},
+        empty = {"", 0, "other"},
+    )]
+    fn should_find_role(text: &str, cursor: usize, expected: &str) {
+        let role = with_context(text, cursor, |context, show| match context.role {
+            Role::CommandName { command } => format!("command name of `{}`", show(command)),
+            Role::Flag(flag) => format!("flag `{}`", show(flag)),
+            Role::Argument {
+                index, argument, ..
+            } => format!("argument {} `{}`", index, show(argument)),
+            Role::ParameterList(parameters) => format!("parameters `{}`", show(parameters)),
+            Role::CellPath(path) => format!("cell path `{}`", show(path)),
+            Role::Comment(comment) => format!("comment `{}`", show(comment)),
+            Role::RecordKey(key) => format!("record key `{}`", show(key)),
+            Role::Other => "other".to_string(),
+        });
+        pretty_assertions::assert_eq!(role, expected);
+    }
+
+    #[parameterized(
+        bare = {"echo foo", 6, Some((Quote::Bare, "foo"))},
+        single = {"echo 'foo'", 7, Some((Quote::Single, "'foo'"))},
+        double_at_end = {r#"echo "foo""#, 10, Some((Quote::Double, r#""foo""#))},
+        raw = {"echo r#'foo'#", 9, Some((Quote::Raw { hashes: 1 }, "r#'foo'#"))},
+        interpolated = {"echo $'a(1)b'", 7, Some((Quote::SingleInterpolated, "$'a(1)b'"))},
+        unfinished = {"echo 'fo", 7, Some((Quote::Single, "'fo"))},
+        unfinished_interpolated = {r#"echo $"fo"#, 8, Some((Quote::DoubleInterpolated, r#"$"fo"#))},
+        unfinished_raw = {"echo r#'fo", 9, Some((Quote::Raw { hashes: 1 }, "r#'fo"))},
+        not_a_string = {"echo 1", 5, None},
+    )]
+    fn should_find_string(text: &str, cursor: usize, expected: Option<(Quote, &str)>) {
+        let string = with_context(text, cursor, |context, show| {
+            context
+                .string
+                .map(|string| (string.quote, show(string.node)))
+        });
+        pretty_assertions::assert_eq!(
+            string,
+            expected.map(|(quote, text)| (quote, text.to_string()))
+        );
+    }
+
+    #[parameterized(
+        single_command = {"ls", 1, Some("ls"), Some("ls")},
+        later_in_pipeline = {"ls | where size > 1kb", 8, Some("where size > 1kb"), Some("ls | where size > 1kb")},
+        second_statement = {"cd foo; ls -a", 11, Some("ls -a"), Some("ls -a")},
+        in_closure = {"ls | each {|f| $f.name }", 16, Some("$f.name"), Some("$f.name")},
+        in_subexpression = {"echo (ls | length)", 12, Some("length"), Some("ls | length")},
+    )]
+    fn should_find_enclosing(
+        text: &str,
+        cursor: usize,
+        pipe_element: Option<&str>,
+        statement: Option<&str>,
+    ) {
+        let enclosing = with_context(text, cursor, |context, show| {
+            (context.pipe_element.map(show), context.statement.map(show))
+        });
+        pretty_assertions::assert_eq!(
+            enclosing,
+            (
+                pipe_element.map(str::to_string),
+                statement.map(str::to_string)
+            )
+        );
+    }
+}
@@ -1,32 +1,41 @@
 use std::fmt::Display;
+use std::iter;
 
-pub fn pretty_print_tree<'src, 'tree>(
-    src: &'src str,
-    tree: &'tree tree_sitter::Tree,
-) -> TreePrinter<'src, 'tree> {
+pub fn pretty_print_tree<'tree>(src: &[u8], tree: &'tree tree_sitter::Tree) -> TreePrinter<'tree> {
     TreePrinter {
-        src,
+        src: printable(src),
         tree,
         show_node_details: false,
     }
 }
-pub fn pretty_print_tree_details<'src, 'tree>(
-    src: &'src str,
+pub fn pretty_print_tree_details<'tree>(
+    src: &[u8],
     tree: &'tree tree_sitter::Tree,
-) -> TreePrinter<'src, 'tree> {
+) -> TreePrinter<'tree> {
     TreePrinter {
-        src,
+        src: printable(src),
         tree,
         show_node_details: true,
     }
 }
 
-pub struct TreePrinter<'src, 'tree> {
-    src: &'src str,
+// Node spans are in bytes, so each byte that isn't utf-8 shows up as a `?` of its own rather than
+// letting a replacement character shift everything after it
+fn printable(src: &[u8]) -> String {
+    let mut printable = String::with_capacity(src.len());
+    for chunk in src.utf8_chunks() {
+        printable.push_str(chunk.valid());
+        printable.extend(iter::repeat_n('?', chunk.invalid().len()));
+    }
+    printable
+}
+
+pub struct TreePrinter<'tree> {
+    src: String,
     tree: &'tree tree_sitter::Tree,
     show_node_details: bool,
 }
-impl<'src, 'tree> Display for TreePrinter<'src, 'tree> {
+impl<'tree> Display for TreePrinter<'tree> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         use annotate_snippets::{Level, Renderer, Snippet};
         let mut with_depths: Vec<_> = preorder_depth_first_traverse_iter(&self.tree).collect();
@@ -34,7 +43,7 @@ impl<'src, 'tree> Display for TreePrinter<'src, 'tree> {
 
         let msg_arena = bumpalo::Bump::new();
         let mut message = Level::Info.title("Tree sitter parse results:").snippet(
-            Snippet::source(self.src).line_start(1).annotations(
+            Snippet::source(&self.src).line_start(1).annotations(
                 with_depths
                     .iter()
                     .enumerate()
@@ -70,7 +79,7 @@ impl<'src, 'tree> Display for TreePrinter<'src, 'tree> {
                                 ),
                                 &msg_arena,
                             )))
-                            .snippet(Snippet::source(self.src).fold(true).annotation(
+                            .snippet(Snippet::source(&self.src).fold(true).annotation(
                                 Level::Help.span(node.byte_range()).label(
                                     bumpalo::boxed::Box::leak(bumpalo::boxed::Box::new_in(
                                         bumpalo::format!(in &msg_arena,
@@ -0,0 +1,490 @@
+use crate::cmd_line::Buffer;
+use anyhow::{bail, Context, Result};
+use bstr::ByteSlice;
+use std::iter;
+use std::ops::Range;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quote {
+    Single,
+    Double,
+    Backtick,
+    Raw { hashes: usize },
+    SingleInterpolated,
+    DoubleInterpolated,
+    // Not really a quote, but bare words can hold a (restricted) string value too
+    Bare,
+}
+use Quote::*;
+
+impl Quote {
+    // The order cycle_quotes_cli steps through. The raw string's hash count is a placeholder,
+    // see for_value
+    const CYCLE: [Quote; 4] = [Single, Double, Backtick, Raw { hashes: 1 }];
+
+    // The kind of quoted string literal making up all of `text`, if it's a finished one
+    pub fn of_literal(text: &[u8]) -> Option<Quote> {
+        Self::at_start(text).filter(|_| literal_len(text) == Some(text.len()))
+    }
+
+    // The kind of quoted string literal that `text` starts with, finished or not
+    pub fn at_start(text: &[u8]) -> Option<Quote> {
+        match (text.first()?, text.get(1)) {
+            (b'\'', _) => Some(Single),
+            (b'"', _) => Some(Double),
+            (b'`', _) => Some(Backtick),
+            (b'$', Some(b'\'')) => Some(SingleInterpolated),
+            (b'$', Some(b'"')) => Some(DoubleInterpolated),
+            (b'r', _) => raw_string_hashes(text).map(|hashes| Raw { hashes }),
+            _ => None,
+        }
+    }
+
+    pub fn delimiters(self) -> (Vec<u8>, Vec<u8>) {
+        match self {
+            Single => (b"'".to_vec(), b"'".to_vec()),
+            Double => (b"\"".to_vec(), b"\"".to_vec()),
+            Backtick => (b"`".to_vec(), b"`".to_vec()),
+            Raw { hashes } => (
+                iter::once(b'r')
+                    .chain(iter::repeat_n(b'#', hashes))
+                    .chain(iter::once(b'\''))
+                    .collect(),
+                iter::once(b'\'')
+                    .chain(iter::repeat_n(b'#', hashes))
+                    .collect(),
+            ),
+            SingleInterpolated => (b"$'".to_vec(), b"'".to_vec()),
+            DoubleInterpolated => (b"$\"".to_vec(), b"\"".to_vec()),
+            Bare => (Vec::new(), Vec::new()),
+        }
+    }
+
+    // Every other plain kind of quote, in cycle order starting after this one
+    pub fn cycle(self) -> impl Iterator<Item = Quote> {
+        let idx = Self::CYCLE
+            .iter()
+            .position(|quote| std::mem::discriminant(quote) == std::mem::discriminant(&self));
+        // Kinds that aren't in the cycle get the whole thing
+        let (skip, take) = match idx {
+            Some(idx) => (idx + 1, Self::CYCLE.len() - 1),
+            None => (0, Self::CYCLE.len()),
+        };
+        Self::CYCLE.into_iter().cycle().skip(skip).take(take)
+    }
+
+    // Raw strings need enough #s that the value can't close them early
+    pub fn for_value(self, value: &[u8]) -> Quote {
+        match self {
+            Raw { .. } => Raw {
+                hashes: (1..)
+                    .find(|hashes| !value.contains_str(Raw { hashes: *hashes }.delimiters().1))
+                    .unwrap(),
+            },
+            _ => self,
+        }
+    }
+}
+
+// Number of #s in the delimiters of the raw string starting at the beginning of `text`, if it is
+// one. Unfinished raw strings only need a complete opening delimiter.
+pub fn raw_string_hashes(text: &[u8]) -> Option<usize> {
+    let hashes = text
+        .iter()
+        .skip(1)
+        .take_while(|char| **char == b'#')
+        .count();
+    match (text.first(), text.get(hashes + 1)) {
+        (Some(b'r'), Some(b'\'')) if hashes > 0 => Some(hashes),
+        _ => None,
+    }
+}
+
+// Length of the finished quoted string literal at the start of `text`
+fn literal_len(text: &[u8]) -> Option<usize> {
+    let quote = Quote::at_start(text)?;
+    let (opening, closing) = quote.delimiters();
+    let mut idx = opening.len();
+    loop {
+        let rest = text.get(idx..)?;
+        if rest.starts_with(&closing) {
+            return Some(idx + closing.len());
+        }
+        idx += match (quote, rest.first()?) {
+            (Double | DoubleInterpolated, b'\\') => 2,
+            (SingleInterpolated | DoubleInterpolated, b'(') => interpolation_len(rest)?,
+            _ => 1,
+        };
+    }
+}
+
+// Length of the finished interpolation at the start of `text`, including both parens
+fn interpolation_len(text: &[u8]) -> Option<usize> {
+    let mut depth = 0;
+    let mut idx = 0;
+    loop {
+        let rest = text.get(idx..)?;
+        idx += match rest.first()? {
+            b'(' => {
+                depth += 1;
+                1
+            }
+            b')' if depth == 1 => return Some(idx + 1),
+            b')' => {
+                depth -= 1;
+                1
+            }
+            _ => literal_len(rest).unwrap_or(1),
+        };
+    }
+}
+
+// The value of a string literal's contents, along with the offset into the contents that
+// each byte of the value came from
+#[derive(Debug, PartialEq)]
+pub struct Unescaped {
+    pub value: Vec<u8>,
+    pub source_offsets: Vec<usize>,
+}
+impl Unescaped {
+    // Index into the value of the first byte at or after `content_offset`
+    pub fn value_offset(&self, content_offset: usize) -> usize {
+        self.source_offsets
+            .partition_point(|source_offset| *source_offset < content_offset)
+    }
+}
+
+// Contents of a string literal, along with where each byte of the value starts in them
+#[derive(Debug, PartialEq)]
+pub struct Escaped {
+    pub content: Vec<u8>,
+    pub value_offsets: Vec<usize>,
+}
+impl Escaped {
+    pub fn content_offset(&self, value_offset: usize) -> usize {
+        self.value_offsets
+            .get(value_offset)
+            .copied()
+            .unwrap_or(self.content.len())
+    }
+}
+
+// Interpolations only have a static value if they're empty or hold a single string literal, like
+// the `('(')` that dwim_single_quote escapes parens with
+pub fn unescape(quote: Quote, content: &[u8]) -> Result<Unescaped> {
+    let mut result = Unescaped {
+        value: Vec::with_capacity(content.len()),
+        source_offsets: Vec::with_capacity(content.len()),
+    };
+    let mut idx = 0;
+    while idx < content.len() {
+        let (unescaped, len) = match (quote, content[idx]) {
+            (Double | DoubleInterpolated, b'\\') => unescape_double_sequence(&content[idx..])
+                .with_context(|| format!("Invalid escape at byte {}", idx))?,
+            (SingleInterpolated | DoubleInterpolated, b'(') => {
+                unescape_interpolation(&content[idx..])
+                    .with_context(|| format!("Interpolation at byte {}", idx))?
+            }
+            (_, char) => (vec![char], 1),
+        };
+        result
+            .source_offsets
+            .extend(iter::repeat_n(idx, unescaped.len()));
+        result.value.extend(unescaped);
+        idx += len;
+    }
+    Ok(result)
+}
+
+// Takes the rest of the string starting from a backslash, and returns the escaped bytes along
+// with the length of the escape sequence
+fn unescape_double_sequence(rest: &[u8]) -> Result<(Vec<u8>, usize)> {
+    let escaped = *rest.get(1).with_context(|| "Backslash at end of string")?;
+    let unescaped = match escaped {
+        b'"' | b'\'' | b'\\' | b'/' | b'(' | b')' | b'{' | b'}' | b'$' | b'^' | b'#' | b'|'
+        | b'~' => escaped,
+        b'a' => 0x07,
+        b'b' => 0x08,
+        b'e' => 0x1b,
+        b'f' => 0x0c,
+        b'n' => b'\n',
+        b'r' => b'\r',
+        b't' => b'\t',
+        b'u' => {
+            let close = rest
+                .iter()
+                .position(|char| *char == b'}')
+                .filter(|_| rest.get(2) == Some(&b'{'))
+                .with_context(|| "Unicode escape must look like \\u{XXXX}")?;
+            let code_point = std::str::from_utf8(&rest[3..close])
+                .ok()
+                .filter(|digits| (1..=6).contains(&digits.len()))
+                .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+                .and_then(char::from_u32)
+                .with_context(|| "Invalid unicode code point")?;
+            let mut buf = [0; 4];
+            return Ok((
+                code_point.encode_utf8(&mut buf).as_bytes().to_vec(),
+                close + 1,
+            ));
+        }
+        _ => bail!("Unknown escape \\{}", escaped as char),
+    };
+    Ok((vec![unescaped], 2))
+}
+
+// Takes the rest of the string starting from an open paren
+fn unescape_interpolation(rest: &[u8]) -> Result<(Vec<u8>, usize)> {
+    let len = interpolation_len(rest).with_context(|| "Unfinished interpolation")?;
+    let inner = rest[1..len - 1].trim_with(|char| char.is_ascii_whitespace());
+    if inner.is_empty() {
+        return Ok((Vec::new(), len));
+    }
+    let quote = Quote::of_literal(inner)
+        .with_context(|| format!("{:?} is not a string literal", inner.as_bstr()))?;
+    let (opening, closing) = quote.delimiters();
+    let unescaped = unescape(quote, &inner[opening.len()..inner.len() - closing.len()])?;
+    Ok((unescaped.value, len))
+}
+
+// None if `quote` can't represent `value`
+pub fn escape(quote: Quote, value: &[u8]) -> Option<Escaped> {
+    let representable = match quote {
+        Single => !value.contains(&b'\''),
+        Backtick => !value.contains(&b'`'),
+        Raw { .. } => !value.contains_str(quote.delimiters().1),
+        Bare => {
+            !value.is_empty()
+                && value.first() != Some(&b'-')
+                && !value
+                    .iter()
+                    .any(|char| char.is_ascii_whitespace() || b"'\"`()[]{}|;#$,".contains(char))
+                && !is_other_literal(value)
+        }
+        Double | SingleInterpolated | DoubleInterpolated => true,
+    };
+    if !representable {
+        return None;
+    }
+    let mut result = Escaped {
+        content: Vec::with_capacity(value.len()),
+        value_offsets: Vec::with_capacity(value.len()),
+    };
+    for char in value {
+        result.value_offsets.push(result.content.len());
+        let escaped: &[u8] = match (quote, char) {
+            (Double | DoubleInterpolated, b'"') => br#"\""#,
+            (Double | DoubleInterpolated, b'\\') => br#"\\"#,
+            (DoubleInterpolated, b'(') => br#"\("#,
+            // Keep terminal control characters visible
+            (Double | DoubleInterpolated, 0x07) => br#"\a"#,
+            (Double | DoubleInterpolated, 0x08) => br#"\b"#,
+            (Double | DoubleInterpolated, 0x1b) => br#"\e"#,
+            (Double | DoubleInterpolated, 0x0c) => br#"\f"#,
+            (Double | DoubleInterpolated, b'\r') => br#"\r"#,
+            (SingleInterpolated, b'(') => br#"('(')"#,
+            (SingleInterpolated, b'\'') => br#"("'")"#,
+            _ => std::slice::from_ref(char),
+        };
+        result.content.extend_from_slice(escaped);
+    }
+    Some(result)
+}
+
+// Whether nushell would read `value` as a bare word of some other type. Anything starting like a
+// number counts, which covers file sizes, durations, dates and ranges along with the numbers
+// themselves.
+fn is_other_literal(value: &[u8]) -> bool {
+    let starts_like_number = match value {
+        [b'0'..=b'9', ..] | [b'+' | b'.', b'0'..=b'9', ..] => true,
+        [b'.', b'.', next, ..] => matches!(next, b'0'..=b'9' | b'<' | b'='),
+        _ => false,
+    };
+    starts_like_number
+        || matches!(value, b"true" | b"false" | b"null")
+        // Like nushell, rust reads inf and NaN as floats
+        || value
+            .to_str()
+            .is_ok_and(|value| value.replace('_', "").parse::<f64>().is_ok())
+}
+
+// Rewrites the contents of a string literal from one kind of quote to another, keeping the cursor
+// on the same byte of the value if it's in the contents. The delimiters are left alone.
+pub fn convert_content<B: Buffer>(
+    mut cmd_line: B,
+    content_range: Range<usize>,
+    from: Quote,
+    to: Quote,
+) -> Result<B> {
+    let unescaped = unescape(from, &cmd_line.slice(content_range.clone()))
+        .with_context(|| format!("Reading {:?} string contents", from))?;
+    let escaped = escape(to, &unescaped.value).with_context(|| {
+        format!(
+            "{:?} strings can't hold {:?}",
+            to,
+            unescaped.value.as_bstr()
+        )
+    })?;
+    let cursor_in_content =
+        (content_range.start..=content_range.end).contains(&cmd_line.cursor_pos());
+    let new_cursor_pos = content_range.start
+        + escaped.content_offset(
+            unescaped.value_offset(cmd_line.cursor_pos().saturating_sub(content_range.start)),
+        );
+    cmd_line.overwrite_range(content_range, &escaped.content);
+    if cursor_in_content {
+        cmd_line.set_cursor_pos(new_cursor_pos);
+    }
+    Ok(cmd_line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd_line::{str_repr, Bytes, Rope};
+    use proptest::prelude::*;
+    use yare::parameterized;
+
+    fn any_quote() -> impl Strategy<Value = Quote> {
+        prop_oneof![
+            Just(Single),
+            Just(Double),
+            Just(Backtick),
+            (1..4usize).prop_map(|hashes| Raw { hashes }),
+            Just(SingleInterpolated),
+            Just(DoubleInterpolated),
+            Just(Bare),
+        ]
+    }
+    fn any_value() -> impl Strategy<Value = String> {
+        prop_oneof!["[a-c'\"`\\\\()#$ \t\n\r\x1b🍳-]*", ".*"]
+    }
+
+    #[parameterized(
+        single = {Single, "a\"b\\", "a\"b\\"},
+        double_escapes = {Double, r#"a\"b\\\n\u{1F373}"#, "a\"b\\\n🍳"},
+        backtick = {Backtick, "a'b", "a'b"},
+        raw = {Raw { hashes: 1 }, r#"a'b\n"#, r#"a'b\n"#},
+        single_interpolated_paren = {SingleInterpolated, r#"('(')ab("'")"#, "(ab'"},
+        double_interpolated_paren = {DoubleInterpolated, r#"\(ab\""#, "(ab\""},
+        empty_interpolation = {DoubleInterpolated, "a( )b", "ab"},
+        nested_literal_interpolation = {DoubleInterpolated, r#"a( "()" )b"#, "a()b"},
+    )]
+    fn should_unescape(quote: Quote, content: &str, expected: &str) {
+        pretty_assertions::assert_eq!(
+            unescape(quote, content.as_bytes()).unwrap().value.as_bstr(),
+            expected.as_bytes().as_bstr()
+        );
+    }
+
+    #[parameterized(
+        unknown_escape = {Double, r#"\q"#},
+        trailing_backslash = {Double, "\\"},
+        bad_code_point = {Double, r#"\u{110000}"#},
+        unfinished_unicode_escape = {Double, r#"\u{41"#},
+        real_interpolation = {DoubleInterpolated, "($foo)"},
+        unfinished_interpolation = {SingleInterpolated, "('('"},
+    )]
+    fn should_fail_to_unescape(quote: Quote, content: &str) {
+        assert!(unescape(quote, content.as_bytes()).is_err());
+    }
+
+    #[parameterized(
+        single_to_double = {str_repr(r#"a"b|c"#), Single, Double, str_repr(r#"a\"b|c"#)},
+        double_to_single = {str_repr(r#"a\(b|c"#), Double, Single, str_repr("a(b|c")},
+        raw_to_double_interpolated = {str_repr(r#""\(|"#), Raw { hashes: 1 }, DoubleInterpolated, str_repr(r#"\"\\\(|"#)},
+        backtick_to_single_interpolated = {str_repr("(a|'"), Backtick, SingleInterpolated, str_repr(r#"('(')a|("'")"#)},
+        cursor_before_escape = {str_repr("a|(b"), Bare, DoubleInterpolated, str_repr(r#"a|\(b"#)},
+        cursor_after_escape = {str_repr(r#"a\(|b"#), DoubleInterpolated, Backtick, str_repr("a(|b")},
+    )]
+    fn should_convert_content(before: Rope, from: Quote, to: Quote, expected: Rope) {
+        let range = 0..before.len();
+        pretty_assertions::assert_eq!(convert_content(before, range, from, to).unwrap(), expected);
+    }
+
+    #[test]
+    fn should_only_convert_content_range() {
+        pretty_assertions::assert_eq!(
+            convert_content(str_repr::<_, Rope>(r#"'a"|' "b""#), 1..3, Single, Double).unwrap(),
+            str_repr(r#"'a\"|' "b""#)
+        );
+        pretty_assertions::assert_eq!(
+            convert_content(str_repr::<_, Rope>(r#"'a"' "b"|"#), 1..3, Single, Double).unwrap(),
+            str_repr(r#"'a\"' "b"|"#)
+        );
+    }
+
+    #[parameterized(
+        word = {"foo", true},
+        path = {"../src", true},
+        digit_later = {"a1", true},
+        int = {"123", false},
+        float = {"1.5", false},
+        leading_dot_float = {".5", false},
+        exponent = {"1e3", false},
+        infinity = {"inf", false},
+        not_a_number = {"NaN", false},
+        file_size = {"1kb", false},
+        duration = {"10sec", false},
+        date = {"2024-01-01", false},
+        range = {"1..5", false},
+        open_range = {"..5", false},
+        bool = {"true", false},
+        null = {"null", false},
+    )]
+    fn should_only_be_bare_if_still_a_string(value: &str, representable: bool) {
+        pretty_assertions::assert_eq!(escape(Bare, value.as_bytes()).is_some(), representable);
+    }
+
+    #[test]
+    fn should_pick_enough_raw_hashes() {
+        pretty_assertions::assert_eq!(Raw { hashes: 1 }.for_value(b"'# '##"), Raw { hashes: 3 });
+    }
+
+    proptest! {
+        #[test]
+        fn roundtrip_escape(quote in any_quote(), value in any_value()) {
+            if let Some(escaped) = escape(quote, value.as_bytes()) {
+                let unescaped = unescape(quote, &escaped.content).unwrap();
+                prop_assert_eq!(unescaped.value.as_bstr(), value.as_bytes().as_bstr());
+            }
+        }
+    }
+    proptest! {
+        #[test]
+        fn escaped_literal_is_finished(quote in any_quote(), value in any_value()) {
+            prop_assume!(quote != Bare);
+            if let Some(escaped) = escape(quote, value.as_bytes()) {
+                let (opening, closing) = quote.delimiters();
+                let literal = [opening, escaped.content, closing].concat();
+                prop_assert_eq!(Quote::of_literal(&literal), Some(quote));
+            }
+        }
+    }
+    proptest! {
+        #[test]
+        fn convert_preserves_value_and_cursor(
+            (from, to, value, value_cursor_pos) in (any_quote(), any_quote(), any_value())
+                .prop_flat_map(|(from, to, value)| {
+                    let len = value.len();
+                    (Just(from), Just(to), Just(value), 0..(len + 1))
+                })
+        ) {
+            let Some(escaped) = escape(from, value.as_bytes()) else {
+                return Ok(());
+            };
+            let cursor_pos = escaped.content_offset(value_cursor_pos);
+            let original = Rope::try_from(Bytes::new(escaped.content, cursor_pos)).unwrap();
+            let range = 0..original.len();
+            let result = convert_content(original, range, from, to);
+            if escape(to, value.as_bytes()).is_none() {
+                prop_assert!(result.is_err());
+                return Ok(());
+            }
+            let converted = result.unwrap();
+            let unescaped = unescape(to, &converted.contents()).unwrap();
+            prop_assert_eq!(unescaped.value.as_bstr(), value.as_bytes().as_bstr());
+            prop_assert_eq!(unescaped.value_offset(converted.cursor_pos), value_cursor_pos);
+        }
+    }
+}
@@ -3,17 +3,27 @@
 mod cmd_line;
 use std::ops::Range;
 
-use cmd_line::ToStrRepr;
+use cmd_line::{Buffer, ToStrRepr};
+mod context;
 mod debug;
+mod escape;
+mod motion;
+mod plugin;
+mod protocol;
+mod selection;
+mod server;
+mod string_value;
+mod syntax;
+mod text_object;
 
 use anyhow::{Context, Result};
+use bstr::ByteSlice;
 use clap::Parser;
 use log::{debug, log_enabled, trace};
 #[cfg(test)]
 use pretty_assertions::{assert_eq, assert_ne};
 use tree_sitter::Node;
 use yfnutool_macros::nu_kind_sym;
-//use tree_sitter::{InputEdit, Language, Point};
 
 // Dummy wrapper to implement "Orphan" instances
 struct Id<T>(T);
@@ -25,6 +35,109 @@ struct Cli {
     verbose: clap_verbosity_flag::Verbosity,
     #[arg(long)]
     test_string: Option<String>,
+    /// Text object for delete, change and yank to act on, like inner-string or around-argument
+    #[arg(long)]
+    text_object: Option<text_object::TextObject>,
+    /// Keep running, answering length-delimited requests on stdin
+    #[arg(long)]
+    server: bool,
+    /// Speak nushell's plugin protocol on stdin/stdout, as nushell asks for when running plugins
+    #[arg(long)]
+    stdio: bool,
+    /// Operation to run against --test-string (requests on stdin name their own)
+    #[arg(value_enum, default_value_t = Operation::Interpolate)]
+    operation: Operation,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum Operation {
+    /// Turn the string at the cursor into an interpolated string
+    Interpolate,
+    /// Turn an interpolated string with no interpolations back into a plain string
+    Deinterpolate,
+    /// Switch the string at the cursor to the next kind of quotes that can hold its value
+    CycleQuotes,
+    /// Select the smallest syntax node around the selection
+    ExpandSelection,
+    /// Select the biggest syntax node inside the selection, on the cursor's side
+    ShrinkSelection,
+    /// Move to the start of the next argument or flag
+    NextArgument,
+    /// Move to the start of the previous argument or flag
+    PreviousArgument,
+    /// Move to the start of the next pipeline element
+    NextPipeElement,
+    /// Move to the start of the previous pipeline element
+    PreviousPipeElement,
+    /// Move to the start of the next statement
+    NextStatement,
+    /// Move to the start of the previous statement
+    PreviousStatement,
+    /// Move from the bracket at or after the cursor to the one it pairs with
+    MatchingBracket,
+    /// Take out the text object at the cursor
+    Delete,
+    /// Take out the text object at the cursor, to type its replacement
+    Change,
+    /// Copy the text object at the cursor, moving to its start
+    Yank,
+}
+
+type Transform<B> = fn(&mut tree_sitter::Parser, syntax::Parsed<B>) -> Result<syntax::Parsed<B>>;
+
+impl Operation {
+    // Text object operations have no transform of their own, needing to be told what to act on
+    fn transform<B: Buffer>(self) -> Option<Transform<B>> {
+        Some(match self {
+            Operation::Interpolate => dwim_interpolate_cli,
+            Operation::Deinterpolate => dwim_deinterpolate_cli,
+            Operation::CycleQuotes => cycle_quotes_cli,
+            Operation::ExpandSelection => selection::expand_selection_cli,
+            Operation::ShrinkSelection => selection::shrink_selection_cli,
+            Operation::NextArgument => motion::next_argument_cli,
+            Operation::PreviousArgument => motion::previous_argument_cli,
+            Operation::NextPipeElement => motion::next_pipe_element_cli,
+            Operation::PreviousPipeElement => motion::previous_pipe_element_cli,
+            Operation::NextStatement => motion::next_statement_cli,
+            Operation::PreviousStatement => motion::previous_statement_cli,
+            Operation::MatchingBracket => motion::matching_bracket_cli,
+            Operation::Delete | Operation::Change | Operation::Yank => return None,
+        })
+    }
+
+    // Hands back the text a text object operation covered along with the result
+    fn apply<B: Buffer>(
+        self,
+        parser: &mut tree_sitter::Parser,
+        input: syntax::Parsed<B>,
+        text_object: Option<text_object::TextObject>,
+    ) -> Result<(syntax::Parsed<B>, Option<bstr::BString>)> {
+        if let Some(transform) = self.transform() {
+            return Ok((transform(parser, input)?, None));
+        }
+        let action = match self {
+            Operation::Delete => text_object::Action::Delete,
+            Operation::Change => text_object::Action::Change,
+            Operation::Yank => text_object::Action::Yank,
+            _ => unreachable!("{:?} has a transform", self),
+        };
+        let object = text_object.with_context(|| format!("{:?} needs a text object", self))?;
+        text_object::act(parser, input, action, object)
+    }
+
+    // Operations that never change the text, just where the cursor is
+    fn is_motion(self) -> bool {
+        matches!(
+            self,
+            Operation::NextArgument
+                | Operation::PreviousArgument
+                | Operation::NextPipeElement
+                | Operation::PreviousPipeElement
+                | Operation::NextStatement
+                | Operation::PreviousStatement
+                | Operation::MatchingBracket
+        )
+    }
 }
 
 fn main() -> Result<()> {
@@ -32,111 +145,130 @@ fn main() -> Result<()> {
     env_logger::Builder::new()
         .filter_level(cli.verbose.log_level_filter())
         .init();
+    let mut parser = nu_parser()?;
+    if cli.server {
+        return server::serve(
+            &mut parser,
+            std::io::stdin().lock(),
+            std::io::stdout().lock(),
+        );
+    }
+    if cli.stdio {
+        return plugin::serve(
+            &mut parser,
+            std::io::stdin().lock(),
+            std::io::stdout().lock(),
+        );
+    }
     match cli.test_string {
         Some(str) => {
-            let cmd_line: cmd_line::Bytes = cmd_line::str_repr(str.clone());
-            let result = dwim_interpolate_cli(cmd_line)
+            let cmd_line: cmd_line::Rope = cmd_line::str_repr(str.clone());
+            let (result, taken) = cli
+                .operation
+                .apply(&mut parser, syntax::Parsed::new(cmd_line), cli.text_object)
                 .with_context(|| format!("Error running against {:?}", str))?;
 
-            println!("{}", result.to_str_repr());
+            println!("{}", result.buffer.to_str_repr());
+            if let Some(taken) = taken {
+                println!("{}", taken);
+            }
         }
         None => {
-            let (cursor_pos_grapheme, text) = rmp_serde::decode::from_read(std::io::stdin())
-                .with_context(|| "Unable to read from stdin")?;
-            let bytes_cli = dwim_interpolate_cli(
-                cmd_line::Utf8 {
-                    text,
-                    cursor_pos_grapheme,
-                }
-                .into(),
-            )?;
-            let utf8_cli: cmd_line::Utf8 = bytes_cli
-                .try_into()
-                .with_context(|| "dwim_interpolate_cli returned invalid utf8")?;
-            rmp_serde::encode::write(
-                &mut std::io::stdout(),
-                &(utf8_cli.cursor_pos_grapheme, utf8_cli.text),
-            )?;
+            // Whoever sent the request is waiting on a response, even for one that didn't decode
+            let response = match rmp_serde::decode::from_read(std::io::stdin()) {
+                Ok(request) => protocol::handle(&mut parser, &request),
+                Err(err) => protocol::Response::undecodable(format!(
+                    "Unable to read request from stdin: {}",
+                    err
+                )),
+            };
+            rmp_serde::encode::write_named(&mut std::io::stdout(), &response)?;
         }
     }
     Ok(())
 }
 
-// h r#'
-// ^ boring
-//  ^ boring
-//   ^ maybe an r#' ?
-//    ^ maybe an r#' ?
-//     ^ An r#'!
+// The output keeps the edits the operation made. Rope only holds utf-8, so anything else gets
+// transformed as plain bytes.
+fn run(
+    parser: &mut tree_sitter::Parser,
+    operation: Operation,
+    input: syntax::Parsed<cmd_line::Bytes>,
+    text_object: Option<text_object::TextObject>,
+) -> Result<(syntax::Parsed<cmd_line::Bytes>, Option<bstr::BString>)> {
+    if input.buffer.text.is_utf8() {
+        let rope_cli = input.try_map(cmd_line::Rope::try_from)?;
+        let (output, taken) = operation.apply(parser, rope_cli, text_object)?;
+        Ok((output.try_map(|rope| Ok(rope.into()))?, taken))
+    } else {
+        operation.apply(parser, input, text_object)
+    }
+}
 
-fn dwim_interpolate_cli(mut input: cmd_line::Bytes) -> Result<cmd_line::Bytes> {
+fn nu_parser() -> Result<tree_sitter::Parser> {
     let mut parser = tree_sitter::Parser::new();
     parser
         .set_language(&tree_sitter_nu::LANGUAGE.into())
         .with_context(|| "Error loading nu grammar")?;
+    Ok(parser)
+}
 
+// Passing the tree `input` was last parsed into, edited to match, only reparses what changed
+fn parse_cli(
+    parser: &mut tree_sitter::Parser,
+    input: &impl Buffer,
+    old_tree: Option<&tree_sitter::Tree>,
+) -> Result<tree_sitter::Tree> {
     let tree = parser
-        .parse(&input.text, None)
+        .parse_with_options(&mut |pos, _| input.chunk_at(pos), old_tree, None)
         .with_context(|| "Tree-sitter unable to parse tree from input")?;
 
-    if input.text == b"" {
-        return Ok(cmd_line::str_repr(r#"$"(|)""#));
-    }
-
-    // Handle the edge case where the cursor is off the end of the
-    let cursor_at_end = input.cursor_pos == input.text.len();
-    let effective_cursor_pos = if cursor_at_end {
-        input.cursor_pos - 1
-    } else {
-        input.cursor_pos
-    };
-
-    let innermost_node = tree
-        .root_node()
-        .named_descendant_for_byte_range(effective_cursor_pos, effective_cursor_pos)
-        .with_context(|| {
-            format!(
-                "Unable to find node at cursor position {}",
-                effective_cursor_pos
-            )
-        })?;
     if log_enabled!(log::Level::Debug) {
         if log_enabled!(log::Level::Trace) {
             trace!(
                 "{}",
-                debug::pretty_print_tree_details(
-                    String::from_utf8_lossy(&input.text).as_ref(),
-                    &tree
-                )
+                debug::pretty_print_tree_details(&input.contents(), &tree)
             );
         } else {
-            debug!(
-                "{}",
-                debug::pretty_print_tree(String::from_utf8_lossy(&input.text).as_ref(), &tree)
-            );
+            debug!("{}", debug::pretty_print_tree(&input.contents(), &tree));
         }
     }
+    Ok(tree)
+}
 
-    match (
-        innermost_node.kind_id(),
-        input.text[innermost_node.start_byte()],
-    ) {
-        (nu_kind_sym!("val_string") | nu_kind_sym!("ERROR"), b'\'') => {
-            return Ok(dwim_single_quote(input, innermost_node.byte_range()));
-        }
-        (nu_kind_sym!("val_string") | nu_kind_sym!("ERROR"), b'\"') => {
-            return Ok(dwim_double_quote(input, innermost_node.byte_range()));
+fn dwim_interpolate_cli<B: Buffer>(
+    parser: &mut tree_sitter::Parser,
+    mut input: syntax::Parsed<B>,
+) -> Result<syntax::Parsed<B>> {
+    let recovered = input.recovered_tree(parser)?;
+
+    if input.is_empty() {
+        input.overwrite_range(0..0, br#"$"("#);
+        input.replace(3..3, br#")""#, cmd_line::Gravity::Left);
+        return Ok(input);
+    }
+
+    let context = context::classify(&recovered, &input)?;
+
+    if let Some(string) = context.string {
+        let string_range = recovered.range(string.node);
+        match string.quote {
+            escape::Quote::Single => return Ok(dwim_single_quote(input, string_range)),
+            escape::Quote::Double => return Ok(dwim_double_quote(input, string_range)),
+            escape::Quote::Raw { .. } => return Ok(dwim_raw_string(input, string_range)),
+            escape::Quote::Bare => return Ok(dwim_bare_word(input, string_range)),
+            // Already interpolated, or a backtick string which can't be
+            _ => (),
         }
-        _ => (),
     }
 
     // Handle the case where we're in an ERROR that runs to the end of the buffer, which could be
-    // a string
-    if let Some(error_parent) = parent_with_kind(innermost_node, nu_kind_sym!("ERROR")) {
+    // a string that no closer made parse
+    if let Some(error_parent) = parent_with_kind(context.node, nu_kind_sym!("ERROR")) {
         trace!("In ERROR node");
-        let error_range = error_parent.byte_range();
-        if error_range.end == input.text.len() {
-            match input.text[error_range.start] {
+        let error_range = recovered.range(error_parent);
+        if error_range.end == input.len() {
+            match input.byte(error_range.start) {
                 b'\'' => {
                     // This branch does not appear to get hit in practice
                     return Ok(dwim_single_quote(input, error_range));
@@ -144,6 +276,9 @@ fn dwim_interpolate_cli(mut input: cmd_line::Bytes) -> Result<cmd_line::Bytes> {
                 b'"' => {
                     return Ok(dwim_double_quote(input, error_range));
                 }
+                b'r' if escape::raw_string_hashes(&input.slice(error_range.clone())).is_some() => {
+                    return Ok(dwim_raw_string(input, error_range));
+                }
                 _ => (),
             }
         }
@@ -153,38 +288,32 @@ fn dwim_interpolate_cli(mut input: cmd_line::Bytes) -> Result<cmd_line::Bytes> {
     Ok(input)
 }
 
-fn dwim_single_quote(
-    mut input: cmd_line::Bytes,
-    existing_single_quote_range: Range<usize>,
-) -> cmd_line::Bytes {
+fn dwim_single_quote<B: Buffer>(mut input: B, existing_single_quote_range: Range<usize>) -> B {
     debug!("Single quote string");
-    {
-        trace!("Escaping parens");
-        let mut idx = existing_single_quote_range.start;
-        while idx < existing_single_quote_range.end {
-            match input.text[idx] {
-                b'(' => {
-                    const REPLACEMENT: &[u8] = br#"('(')"#;
-                    input.overwrite_range(idx..idx + 1, REPLACEMENT);
-                    idx += REPLACEMENT.len();
-                }
-                _ => {
-                    idx += 1;
-                }
-            }
+    let mut transaction = cmd_line::Transaction::new();
+    trace!("Escaping parens");
+    for idx in existing_single_quote_range.clone() {
+        if input.byte(idx) == b'(' {
+            transaction
+                .replace(idx..idx + 1, br#"('(')"#, cmd_line::Gravity::Right)
+                .expect("Edits don't overlap");
         }
     }
-    input.insert_push_cursor(existing_single_quote_range.start, b'$');
-    input.insert_push_cursor(input.cursor_pos, b'(');
-    input.insert_no_push_cursor(input.cursor_pos, b')');
+    transaction
+        .replace(
+            existing_single_quote_range.start..existing_single_quote_range.start,
+            b"$",
+            cmd_line::Gravity::Right,
+        )
+        .expect("Edits don't overlap");
+    insert_interpolation(&mut transaction, input.cursor_pos());
+    transaction.apply(&mut input);
     input
 }
 
-fn dwim_double_quote(
-    mut input: cmd_line::Bytes,
-    existing_double_quote_range: Range<usize>,
-) -> cmd_line::Bytes {
+fn dwim_double_quote<B: Buffer>(mut input: B, existing_double_quote_range: Range<usize>) -> B {
     debug!("Double quote string");
+    let mut transaction = cmd_line::Transaction::new();
     {
         trace!("Escaping parens");
         let mut idx = existing_double_quote_range.start;
@@ -195,96 +324,333 @@ fn dwim_double_quote(
         use State::*;
         let mut state = Normal;
         while idx < existing_double_quote_range.end {
-            match (state, input.text[idx]) {
+            match (state, input.byte(idx)) {
                 (Normal, b'\\') => {
                     state = Escaped;
-                    idx += 1;
                 }
                 (Normal, b'(') => {
-                    const REPLACEMENT: &[u8] = br#"\("#;
-                    input.overwrite_range(idx..idx + 1, REPLACEMENT);
+                    transaction
+                        .replace(idx..idx + 1, br#"\("#, cmd_line::Gravity::Right)
+                        .expect("Edits don't overlap");
                     state = Normal;
-                    idx += REPLACEMENT.len();
                 }
                 _ => {
                     state = Normal;
-                    idx += 1;
                 }
             }
+            idx += 1;
         }
     }
-    input.insert_push_cursor(existing_double_quote_range.start, b'$');
-    input.insert_push_cursor(input.cursor_pos, b'(');
-    input.insert_no_push_cursor(input.cursor_pos, b')');
+    transaction
+        .replace(
+            existing_double_quote_range.start..existing_double_quote_range.start,
+            b"$",
+            cmd_line::Gravity::Right,
+        )
+        .expect("Edits don't overlap");
+    insert_interpolation(&mut transaction, input.cursor_pos());
+    transaction.apply(&mut input);
     input
 }
 
-mod escape {
-    use super::*;
-    use std::ops::Range;
-    fn double_to_double_interpolate(
-        mut cmd_line: cmd_line::Bytes,
-        range: Range<usize>,
-    ) -> cmd_line::Bytes {
-        let mut idx = range.start;
-        while idx < range.end {
-            match cmd_line.text[idx] {
-                b'(' => {
-                    cmd_line.insert_push_cursor(idx, b'\\');
-                    idx += 1;
-                }
-                _ => (),
-            }
+fn dwim_raw_string<B: Buffer>(mut input: B, existing_raw_string_range: Range<usize>) -> B {
+    debug!("Raw string");
+    let hashes = escape::raw_string_hashes(&input.slice(existing_raw_string_range.clone()))
+        .expect("dwim_raw_string called on something other than a raw string");
+    let quote = escape::Quote::Raw { hashes };
+    let (opening, closing) = quote.delimiters();
+    let content_start = existing_raw_string_range.start + opening.len();
+    let has_closing = existing_raw_string_range.end - content_start >= closing.len()
+        && input
+            .slice(existing_raw_string_range.clone())
+            .ends_with(&closing);
+    let content_end = if has_closing {
+        existing_raw_string_range.end - closing.len()
+    } else {
+        existing_raw_string_range.end
+    };
 
-            idx += 1;
+    // Keep the interpolation from landing in a delimiter
+    input.set_cursor_pos(input.cursor_pos().clamp(content_start, content_end));
+
+    // Converting the contents moves the cursor to the same place in the value, which no edit
+    // against the original text can say, so the delimiters are changed afterwards
+    trace!("Escaping raw content");
+    let original_len = input.len();
+    input = escape::convert_content(
+        input,
+        content_start..content_end,
+        quote,
+        escape::Quote::DoubleInterpolated,
+    )
+    .expect("Raw string contents always fit in a double quoted string");
+    let content_end = content_end + input.len() - original_len;
+    let string_end = existing_raw_string_range.end + input.len() - original_len;
+
+    let mut transaction = cmd_line::Transaction::new();
+    transaction
+        .replace(
+            existing_raw_string_range.start..content_start,
+            b"$\"",
+            cmd_line::Gravity::Right,
+        )
+        .expect("Edits don't overlap");
+    insert_interpolation(&mut transaction, input.cursor_pos());
+    if has_closing {
+        transaction
+            .replace(content_end..string_end, b"\"", cmd_line::Gravity::Right)
+            .expect("Edits don't overlap");
+    }
+    transaction.apply(&mut input);
+    input
+}
+
+fn dwim_bare_word<B: Buffer>(mut input: B, existing_bare_word_range: Range<usize>) -> B {
+    debug!("Bare word");
+    let original_len = input.len();
+    trace!("Escaping bare word");
+    input = escape::convert_content(
+        input,
+        existing_bare_word_range.clone(),
+        escape::Quote::Bare,
+        escape::Quote::DoubleInterpolated,
+    )
+    .expect("Bare words always fit in a double quoted string");
+    let end = existing_bare_word_range.end + input.len() - original_len;
+
+    let mut transaction = cmd_line::Transaction::new();
+    transaction
+        .replace(
+            existing_bare_word_range.start..existing_bare_word_range.start,
+            b"$\"",
+            cmd_line::Gravity::Right,
+        )
+        .expect("Edits don't overlap");
+    insert_interpolation(&mut transaction, input.cursor_pos());
+    // After the interpolation if the cursor was at the end, and without taking the cursor along
+    transaction
+        .replace(end..end, b"\"", cmd_line::Gravity::Left)
+        .expect("Edits don't overlap");
+    transaction.apply(&mut input);
+    input
+}
+
+// An empty interpolation at `pos`, which the cursor ends up inside if it's there
+fn insert_interpolation(transaction: &mut cmd_line::Transaction, pos: usize) {
+    transaction
+        .replace(pos..pos, b"(", cmd_line::Gravity::Right)
+        .expect("Edits don't overlap");
+    transaction
+        .replace(pos..pos, b")", cmd_line::Gravity::Left)
+        .expect("Edits don't overlap");
+}
+
+// Undoes dwim_interpolate_cli when none of the interpolations have anything in them
+fn dwim_deinterpolate_cli<B: Buffer>(
+    parser: &mut tree_sitter::Parser,
+    mut input: syntax::Parsed<B>,
+) -> Result<syntax::Parsed<B>> {
+    if input.is_empty() {
+        debug!("Nothing to do");
+        return Ok(input);
+    }
+    let recovered = input.recovered_tree(parser)?;
+    let context = context::classify(&recovered, &input)?;
+
+    // The string at the cursor could be a bare word inside one of the interpolations
+    let interpolated_range = match parent_with_kind(context.node, nu_kind_sym!("val_interpolated"))
+    {
+        Some(interpolated) => Some(recovered.range(interpolated)),
+        // Unfinished interpolated strings at the end of the buffer show up as an ERROR
+        None => parent_with_kind(context.node, nu_kind_sym!("ERROR"))
+            .map(|error_parent| recovered.range(error_parent))
+            .filter(|error_range| error_range.end == input.len()),
+    };
+    if let Some(interpolated_range) = interpolated_range {
+        let prefix = input.slice(
+            interpolated_range.start..(interpolated_range.start + 2).min(interpolated_range.end),
+        );
+        if matches!(prefix.as_ref(), b"$'" | b"$\"") {
+            if let Some(result) = dwim_deinterpolate(input.clone(), interpolated_range) {
+                return Ok(result);
+            }
         }
-        cmd_line
     }
+    debug!("Nothing to do");
+    Ok(input)
+}
 
-    // TODO: think about this more
-    fn single_to_double(mut cmd_line: cmd_line::Bytes, range: Range<usize>) -> cmd_line::Bytes {
-        let mut idx = range.start;
-        while idx < range.end {
-            match cmd_line.text[idx] {
-                b'"' => {
-                    cmd_line.insert_push_cursor(idx, b'\\');
-                    idx += 1;
+// Returns None if the string contains an interpolation that actually does something
+fn dwim_deinterpolate<B: Buffer>(
+    mut input: B,
+    existing_interpolated_range: Range<usize>,
+) -> Option<B> {
+    let text = input.contents();
+    let quote = text[existing_interpolated_range.start + 1];
+    debug!("Interpolated string with quote {:?}", quote as char);
+    // Edits are found left to right against the original text, so they never overlap
+    let mut transaction = cmd_line::Transaction::new();
+    transaction
+        .delete(existing_interpolated_range.start..existing_interpolated_range.start + 1)
+        .expect("First edit");
+    let mut idx = existing_interpolated_range.start + 2;
+    let end = existing_interpolated_range.end;
+    while idx < end {
+        match (quote, text[idx]) {
+            (b'"', b'\\') if text.get(idx + 1) == Some(&b'(') => {
+                trace!("Unescaping paren at {}", idx);
+                transaction
+                    .delete(idx..idx + 1)
+                    .expect("Edits don't overlap");
+                idx += 2;
+            }
+            (b'"', b'\\') => {
+                idx += 2;
+            }
+            (_, b'(') => {
+                let close = idx + text[idx..end].iter().position(|char| *char == b')')?;
+                let contents = &text[idx + 1..close];
+                if contents.iter().all(u8::is_ascii_whitespace) {
+                    trace!("Removing empty interpolation at {}", idx);
+                    transaction
+                        .delete(idx..close + 1)
+                        .expect("Edits don't overlap");
+                } else if contents == b"'('" {
+                    trace!("Unescaping paren at {}", idx);
+                    transaction
+                        .replace(idx..close + 1, b"(", cmd_line::Gravity::Right)
+                        .expect("Edits don't overlap");
+                } else {
+                    debug!("Found a non-empty interpolation");
+                    return None;
                 }
-                _ => (),
+                idx = close + 1;
             }
+            (_, char) if char == quote => break,
+            _ => {
+                idx += 1;
+            }
+        }
+    }
+    transaction.apply(&mut input);
+    Some(input)
+}
 
-            idx += 1;
+fn cycle_quotes_cli<B: Buffer>(
+    parser: &mut tree_sitter::Parser,
+    mut input: syntax::Parsed<B>,
+) -> Result<syntax::Parsed<B>> {
+    if input.is_empty() {
+        debug!("Nothing to do");
+        return Ok(input);
+    }
+    let recovered = input.recovered_tree(parser)?;
+    let context = context::classify(&recovered, &input)?;
+
+    let Some(string_node) = context
+        .string
+        .map(|string| string.node)
+        .filter(|node| node.kind_id() == nu_kind_sym!("val_string"))
+    else {
+        debug!("Nothing to do");
+        return Ok(input);
+    };
+    let string = match string_value::evaluate(string_node, &input.contents()) {
+        Ok(string) => string,
+        Err(err) => {
+            debug!("Unable to evaluate string: {:#}", err);
+            return Ok(input);
         }
-        cmd_line
+    };
+    match cycle_quotes(input.clone(), string) {
+        Some(result) => Ok(result),
+        None => Ok(input),
+    }
+}
+
+// Returns None if there's nothing to switch to
+fn cycle_quotes<B: Buffer>(mut input: B, string: string_value::StringValue) -> Option<B> {
+    if string.quote == escape::Quote::Bare {
+        debug!("Not a quoted string");
+        return None;
     }
+    debug!("{:?} string", string.quote);
+
+    let Some((new_quote, escaped)) = string.quote.cycle().find_map(|candidate| {
+        let candidate = candidate.for_value(&string.value);
+        escape::escape(candidate, &string.value).map(|escaped| (candidate, escaped))
+    }) else {
+        debug!("No other quote can represent the string");
+        return None;
+    };
+    debug!("Switching to {:?} string", new_quote);
 
-    //fn double_to_single(mut cmd_line: CmdLine, range: Range<usize>) -> CmdLine {
-    //    let mut idx = range.start;
-    //    while idx < range.end {
-    //        match cmd_line.text[idx] {
-    //            b'\\' => {
-    //                cmd_line.delete_no_pull_cursor(idx);
-    //                match cmd_line.text[idx] {
-    //                    b'(' => {
-    //                    }
-    //                }
-    //            }
-    //            _ => (),
-    //        }
-    //    }
-    //}
+    let (new_opening, new_closing) = new_quote.delimiters();
+    let cursor_in_string =
+        string.range.start < input.cursor_pos() && input.cursor_pos() < string.range.end;
+    let value_cursor_pos = string.value_offset(
+        input
+            .cursor_pos()
+            .clamp(string.content_range.start, string.content_range.end),
+    );
+    let new_cursor_pos =
+        string.range.start + new_opening.len() + escaped.content_offset(value_cursor_pos);
+    let replacement: Vec<u8> = [new_opening, escaped.content, new_closing].concat();
+    input.overwrite_range(string.range, &replacement);
+    if cursor_in_string {
+        input.set_cursor_pos(new_cursor_pos);
+    }
+    Some(input)
 }
 
 fn parent_with_kind<'tree>(node: Node<'tree>, kind: NodeKindId) -> Option<Node<'tree>> {
-    let mut current = Some(node);
-    std::iter::from_fn(move || match current {
-        Some(ret) => {
-            current = ret.parent();
-            Some(ret)
-        }
-        None => None,
-    })
-    .find(|node| node.kind_id() == kind)
+    context::ancestors(node).find(|node| node.kind_id() == kind)
+}
+
+// Runs the operation on every backend, which all have to agree with the rope
+#[cfg(test)]
+fn on_every_backend(operation: Operation, before: cmd_line::Rope) -> cmd_line::Rope {
+    on_every_backend_with(operation, None, before).0
+}
+
+#[cfg(test)]
+fn on_every_backend_with(
+    operation: Operation,
+    text_object: Option<text_object::TextObject>,
+    before: cmd_line::Rope,
+) -> (cmd_line::Rope, Option<bstr::BString>) {
+    let mut parser = nu_parser().unwrap();
+    let (rope, taken) = operation
+        .apply(
+            &mut parser,
+            syntax::Parsed::new(before.clone()),
+            text_object,
+        )
+        .unwrap();
+    let rope = rope.buffer;
+    let (bytes, bytes_taken) = operation
+        .apply(
+            &mut parser,
+            syntax::Parsed::new(cmd_line::Bytes::from(before.clone())),
+            text_object,
+        )
+        .unwrap();
+    pretty_assertions::assert_eq!(
+        (cmd_line::Rope::try_from(bytes.buffer).unwrap(), bytes_taken),
+        (rope.clone(), taken.clone())
+    );
+    let (utf8, utf8_taken) = operation
+        .apply(
+            &mut parser,
+            syntax::Parsed::new(cmd_line::Utf8::try_from(before).unwrap()),
+            text_object,
+        )
+        .unwrap();
+    pretty_assertions::assert_eq!(
+        (cmd_line::Rope::from(utf8.buffer), utf8_taken),
+        (rope.clone(), taken.clone())
+    );
+    (rope, taken)
 }
 
 #[cfg(test)]
@@ -303,8 +669,11 @@ mod tests {
             just_started_string = {str_repr(r#"'|"#), str_repr(r#"$'(|)"#)},
             //special_case_add_dollarsign = {str_repr(r#"$"(|)""#), str_repr(r#"$"($|)""#)},
         )]
-        fn should_add_interpolation(before: cmd_line::Bytes, expected: cmd_line::Bytes) {
-            pretty_assertions::assert_eq!(dwim_interpolate_cli(before).unwrap(), expected);
+        fn should_add_interpolation(before: cmd_line::Rope, expected: cmd_line::Rope) {
+            pretty_assertions::assert_eq!(
+                on_every_backend(Operation::Interpolate, before),
+                expected
+            );
         }
     }
     mod double_quote {
@@ -318,8 +687,124 @@ mod tests {
             second_unfinished_double_quote_string = {str_repr(r#"foo "🍳" "hello () | "#), str_repr(r#"foo "🍳" $"hello \() (|) "#)},
             //special_case_add_dollarsign = {str_repr(r#"$"(|)""#), str_repr(r#"$"($|)""#)},
         )]
-        fn should_add_interpolation(before: cmd_line::Bytes, expected: cmd_line::Bytes) {
-            pretty_assertions::assert_eq!(dwim_interpolate_cli(before).unwrap(), expected);
+        fn should_add_interpolation(before: cmd_line::Rope, expected: cmd_line::Rope) {
+            pretty_assertions::assert_eq!(
+                on_every_backend(Operation::Interpolate, before),
+                expected
+            );
+        }
+    }
+
+    mod raw_string {
+        use super::*;
+        #[parameterized(
+            simple = {str_repr(r#"r#'|'#"#), str_repr(r#"$"(|)""#)},
+            later_in_cli = {str_repr(r#"foo r#'ba| '#"#), str_repr(r#"foo $"ba(|) ""#)},
+            escape_special_characters = {str_repr(r#"r#'"\(| '#"#), str_repr(r#"$"\"\\\((|) ""#)},
+            more_hashes = {str_repr(r#"r##'it's '#| '##"#), str_repr(r#"$"it's '#(|) ""#)},
+            cursor_in_opening_delimiter = {str_repr(r#"r#|'foo'#"#), str_repr(r#"$"(|)foo""#)},
+            cursor_in_closing_delimiter = {str_repr(r#"r#'foo'|#"#), str_repr(r#"$"foo(|)""#)},
+            just_started_string = {str_repr(r#"r#'|"#), str_repr(r#"$"(|)"#)},
+            second_unfinished_raw_string = {str_repr(r#"foo r#'🍳'# r#'hello () | "#), str_repr(r#"foo r#'🍳'# $"hello \() (|) "#)},
+        )]
+        fn should_add_interpolation(before: cmd_line::Rope, expected: cmd_line::Rope) {
+            pretty_assertions::assert_eq!(
+                on_every_backend(Operation::Interpolate, before),
+                expected
+            );
+        }
+    }
+    mod bare_word {
+        use super::*;
+        #[parameterized(
+            middle_of_word = {str_repr("echo foo|bar"), str_repr(r#"echo $"foo(|)bar""#)},
+            end_of_path = {str_repr("ls src/|"), str_repr(r#"ls $"src/(|)""#)},
+            start_of_word = {str_repr("echo |foo"), str_repr(r#"echo $"(|)foo""#)},
+            escape_backslash = {str_repr(r#"echo a\b|c"#), str_repr(r#"echo $"a\\b(|)c""#)},
+            later_argument = {str_repr("cp foo ba|r"), str_repr(r#"cp foo $"ba(|)r""#)},
+        )]
+        fn should_add_interpolation(before: cmd_line::Rope, expected: cmd_line::Rope) {
+            pretty_assertions::assert_eq!(
+                on_every_backend(Operation::Interpolate, before),
+                expected
+            );
+        }
+        #[parameterized(
+            command_name = {str_repr("ec|ho foo")},
+            flag = {str_repr("ls --al|l")},
+        )]
+        fn should_do_nothing(before: cmd_line::Rope) {
+            pretty_assertions::assert_eq!(
+                on_every_backend(Operation::Interpolate, before.clone()),
+                before
+            );
+        }
+    }
+    mod cycle_quotes {
+        use super::*;
+        #[parameterized(
+            single_to_double = {str_repr("'fo|o'"), str_repr(r#""fo|o""#)},
+            double_to_backtick = {str_repr(r#""fo|o""#), str_repr("`fo|o`")},
+            backtick_to_raw = {str_repr("`fo|o`"), str_repr("r#'fo|o'#")},
+            raw_to_single = {str_repr("r#'fo|o'#"), str_repr("'fo|o'")},
+            unescape_double = {str_repr(r#""a\"b|""#), str_repr(r#"`a"b|`"#)},
+            escape_double = {str_repr(r#"'a"\b|'"#), str_repr(r#""a\"\\b|""#)},
+            skip_single = {str_repr("r#'it's|'#"), str_repr(r#""it's|""#)},
+            skip_backtick = {str_repr(r#""a`b|""#), str_repr("r#'a`b|'#")},
+            raw_needs_more_hashes = {str_repr("`it'#s|`"), str_repr("r##'it'#s|'##")},
+            unicode_escape = {str_repr(r#""\u{1F373}|""#), str_repr("`🍳|`")},
+            cursor_after_escape = {str_repr(r#""\\|a""#), str_repr(r#"`\|a`"#)},
+            cursor_after_string = {str_repr("echo 'foo'|"), str_repr(r#"echo "foo"|"#)},
+            cursor_before_string = {str_repr("echo |'foo'"), str_repr(r#"echo |"foo""#)},
+        )]
+        fn should_change_quotes(before: cmd_line::Rope, expected: cmd_line::Rope) {
+            pretty_assertions::assert_eq!(
+                on_every_backend(Operation::CycleQuotes, before),
+                expected
+            );
+        }
+        #[parameterized(
+            bare_word = {str_repr("echo fo|o")},
+            invalid_escape = {str_repr(r#"echo "\q|""#)},
+            unfinished_string = {str_repr("echo 'fo|o")},
+        )]
+        fn should_do_nothing(before: cmd_line::Rope) {
+            pretty_assertions::assert_eq!(
+                on_every_backend(Operation::CycleQuotes, before.clone()),
+                before
+            );
+        }
+    }
+    mod deinterpolate {
+        use super::*;
+        #[parameterized(
+            simple_double = {str_repr(r#"$"(|)""#), str_repr(r#""|""#)},
+            simple_single = {str_repr(r#"$'(|)'"#), str_repr(r#"'|'"#)},
+            later_in_cli = {str_repr(r#"foo $"ba(|) ""#), str_repr(r#"foo "ba| ""#)},
+            cursor_after_interpolation = {str_repr(r#"$"ba() b|""#), str_repr(r#""ba b|""#)},
+            unescape_double_paren = {str_repr(r#"$"\(ba(|) ""#), str_repr(r#""(ba| ""#)},
+            unescape_single_paren = {str_repr(r#"$'('(')ba(|) '"#), str_repr(r#"'(ba| '"#)},
+            leave_other_escapes = {str_repr(r#"$"\"\\(|)""#), str_repr(r#""\"\\|""#)},
+            unfinished_string = {str_repr(r#"foo $"hello \() (|) "#), str_repr(r#"foo "hello () | "#)},
+        )]
+        fn should_remove_interpolation(before: cmd_line::Rope, expected: cmd_line::Rope) {
+            pretty_assertions::assert_eq!(
+                on_every_backend(Operation::Deinterpolate, before),
+                expected
+            );
+        }
+        #[parameterized(
+            has_variable = {str_repr(r#"$"($foo)|""#)},
+            has_subexpression = {str_repr(r#"$'(ls)|'"#)},
+            plain_string = {str_repr(r#""fo|o""#)},
+            not_a_string = {str_repr(r#"ec|ho"#)},
+            empty_string = {str_repr("|")},
+        )]
+        fn should_do_nothing(before: cmd_line::Rope) {
+            pretty_assertions::assert_eq!(
+                on_every_backend(Operation::Deinterpolate, before.clone()),
+                before
+            );
         }
     }
 
@@ -327,10 +812,7 @@ mod tests {
     fn cli_helper() {
         pretty_assertions::assert_eq!(
             str_repr::<_, cmd_line::Bytes>("h|ello world"),
-            cmd_line::Bytes {
-                text: b"hello world".to_vec().into(),
-                cursor_pos: 1,
-            }
+            cmd_line::Bytes::new(b"hello world".to_vec(), 1)
         )
     }
 }
@@ -0,0 +1,184 @@
+use crate::cmd_line::Buffer;
+use crate::context;
+use crate::syntax::Parsed;
+use anyhow::Result;
+use log::debug;
+use tree_sitter::Node;
+use yfnutool_macros::nu_kind_sym;
+
+// Motions only ever move the cursor, leaving the text (and any selection) as it was
+
+#[derive(Clone, Copy, Debug)]
+enum Direction {
+    Next,
+    Previous,
+}
+
+pub fn next_argument_cli<B: Buffer>(
+    parser: &mut tree_sitter::Parser,
+    input: Parsed<B>,
+) -> Result<Parsed<B>> {
+    move_to_start(parser, input, Direction::Next, is_argument)
+}
+
+pub fn previous_argument_cli<B: Buffer>(
+    parser: &mut tree_sitter::Parser,
+    input: Parsed<B>,
+) -> Result<Parsed<B>> {
+    move_to_start(parser, input, Direction::Previous, is_argument)
+}
+
+pub fn next_pipe_element_cli<B: Buffer>(
+    parser: &mut tree_sitter::Parser,
+    input: Parsed<B>,
+) -> Result<Parsed<B>> {
+    move_to_start(parser, input, Direction::Next, context::is_pipe_element)
+}
+
+pub fn previous_pipe_element_cli<B: Buffer>(
+    parser: &mut tree_sitter::Parser,
+    input: Parsed<B>,
+) -> Result<Parsed<B>> {
+    move_to_start(parser, input, Direction::Previous, context::is_pipe_element)
+}
+
+pub fn next_statement_cli<B: Buffer>(
+    parser: &mut tree_sitter::Parser,
+    input: Parsed<B>,
+) -> Result<Parsed<B>> {
+    move_to_start(parser, input, Direction::Next, context::is_statement)
+}
+
+pub fn previous_statement_cli<B: Buffer>(
+    parser: &mut tree_sitter::Parser,
+    input: Parsed<B>,
+) -> Result<Parsed<B>> {
+    move_to_start(parser, input, Direction::Previous, context::is_statement)
+}
+
+// Moves to the nearest start of a node that `is_target` picks out. Moving back from inside such a
+// node goes to its own start first.
+fn move_to_start<B: Buffer>(
+    parser: &mut tree_sitter::Parser,
+    mut input: Parsed<B>,
+    direction: Direction,
+    is_target: fn(Node) -> bool,
+) -> Result<Parsed<B>> {
+    let recovered = input.recovered_tree(parser)?;
+    let cursor = input.cursor_pos();
+    // Anything starting in the closers isn't in the buffer to move to
+    let starts = context::descendants(recovered.tree.root_node())
+        .filter(|node| is_target(*node))
+        .map(|node| node.start_byte())
+        .filter(|start| *start < input.len());
+    let target = match direction {
+        Direction::Next => starts.filter(|start| *start > cursor).min(),
+        Direction::Previous => starts.filter(|start| *start < cursor).max(),
+    };
+    match target {
+        Some(target) => input.set_cursor_pos(target),
+        None => debug!("Nothing to move to"),
+    }
+    Ok(input)
+}
+
+// Flags count as arguments here, since they're just as much something to skip over
+fn is_argument(node: Node) -> bool {
+    node.is_named()
+        && node
+            .parent()
+            .is_some_and(|parent| parent.kind_id() == nu_kind_sym!("command"))
+        && !matches!(
+            node.kind_id(),
+            nu_kind_sym!("cmd_identifier") | nu_kind_sym!("comment")
+        )
+}
+
+const BRACKETS: [(&str, &str); 3] = [("(", ")"), ("[", "]"), ("{", "}")];
+
+// Jumps from the first bracket at or after the cursor on its line to the one that pairs with it,
+// the way vim's % does
+pub fn matching_bracket_cli<B: Buffer>(
+    parser: &mut tree_sitter::Parser,
+    mut input: Parsed<B>,
+) -> Result<Parsed<B>> {
+    let recovered = input.recovered_tree(parser)?;
+    let cursor = input.cursor_pos();
+    let Some((bracket, partner_kind, opening)) = context::descendants(recovered.tree.root_node())
+        .filter(|node| !node.is_missing() && node.start_byte() >= cursor)
+        .take_while(|node| {
+            node.start_byte() < input.len()
+                && !input.slice(cursor..node.start_byte()).contains(&b'\n')
+        })
+        .find_map(|node| {
+            BRACKETS.iter().find_map(|(open, close)| match node.kind() {
+                kind if kind == *open => Some((node, *close, true)),
+                kind if kind == *close => Some((node, *open, false)),
+                _ => None,
+            })
+        })
+    else {
+        debug!("No bracket to match");
+        return Ok(input);
+    };
+    let partner = bracket.parent().and_then(|parent| {
+        let mut walk = parent.walk();
+        let mut partners = parent
+            .children(&mut walk)
+            .filter(|child| child.kind() == partner_kind && *child != bracket);
+        if opening {
+            partners.last()
+        } else {
+            partners.next()
+        }
+    });
+    // A partner in the closers is one the user hasn't typed yet
+    match partner.filter(|partner| !partner.is_missing() && partner.start_byte() < input.len()) {
+        Some(partner) => input.set_cursor_pos(partner.start_byte()),
+        None => debug!("Bracket isn't matched"),
+    }
+    Ok(input)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cmd_line::{str_repr, Rope};
+    use crate::Operation;
+    use yare::parameterized;
+
+    #[parameterized(
+        next_argument = {Operation::NextArgument, "ls |--all foo", "ls --all |foo"},
+        next_argument_from_command_name = {Operation::NextArgument, "l|s foo bar", "ls |foo bar"},
+        next_argument_in_next_command = {Operation::NextArgument, "ls fo|o | get bar", "ls foo | get |bar"},
+        previous_argument = {Operation::PreviousArgument, "ls foo |bar", "ls |foo bar"},
+        previous_argument_from_inside = {Operation::PreviousArgument, "ls foo ba|r", "ls foo |bar"},
+        next_pipe_element = {Operation::NextPipeElement, "l|s | get foo", "ls | |get foo"},
+        previous_pipe_element = {Operation::PreviousPipeElement, "ls | get fo|o", "ls | |get foo"},
+        next_statement = {Operation::NextStatement, "c|d foo; ls", "cd foo; |ls"},
+        previous_statement = {Operation::PreviousStatement, "cd foo; |ls", "|cd foo; ls"},
+        previous_statement_from_inside = {Operation::PreviousStatement, "cd foo; l|s", "cd foo; |ls"},
+        opening_bracket = {Operation::MatchingBracket, "echo |(ls)", "echo (ls|)"},
+        closing_bracket = {Operation::MatchingBracket, "echo (ls|)", "echo |(ls)"},
+        nested_brackets = {Operation::MatchingBracket, "|[1 [2]]", "[1 [2]|]"},
+        bracket_later_on_line = {Operation::MatchingBracket, "ec|ho [1]", "echo [1|]"},
+    )]
+    fn should_move(operation: Operation, before: &str, after: &str) {
+        pretty_assertions::assert_eq!(
+            crate::on_every_backend(operation, str_repr(before)),
+            str_repr::<_, Rope>(after)
+        );
+    }
+
+    #[parameterized(
+        no_next_argument = {Operation::NextArgument, "ls foo|"},
+        no_previous_statement = {Operation::PreviousStatement, "|ls; cd"},
+        unfinished_bracket = {Operation::MatchingBracket, "echo |(ls"},
+        bracket_on_next_line = {Operation::MatchingBracket, "ec|ho\n(ls)"},
+    )]
+    fn should_stay(operation: Operation, before: &str) {
+        pretty_assertions::assert_eq!(
+            crate::on_every_backend(operation, str_repr(before)),
+            str_repr::<_, Rope>(before)
+        );
+    }
+}
@@ -0,0 +1,700 @@
+use crate::{protocol, Operation};
+use anyhow::{Context, Result};
+use bstr::ByteSlice;
+use clap::ValueEnum;
+use log::debug;
+use serde::{de::IgnoredAny, Deserialize, Serialize, Serializer};
+use std::io::{self, BufReader, Read, Write};
+
+// Just enough of nushell's plugin protocol
+// (https://www.nushell.sh/contributor-book/plugin_protocol_reference.html) to expose each
+// operation as a `yfnutool <operation> --text <buffer> --cursor <graphemes>` command.
+//
+// nushell runs the plugin with `--stdio` and waits for it to name its encoding, then streams
+// msgpack messages in both directions. We only ever answer calls; we never start streams or make
+// engine calls of our own.
+
+const ENCODING: &[u8] = b"msgpack";
+// The nushell release whose protocol this follows
+const PROTOCOL_VERSION: &str = "0.105.1";
+const COMMAND_PREFIX: &str = "yfnutool ";
+
+#[derive(Debug, Deserialize)]
+enum PluginInput {
+    Hello(EngineHello),
+    Call(u64, PluginCall),
+    Goodbye,
+    // Only sent in reply to things we never do, but harmless to skip
+    EngineCallResponse(IgnoredAny),
+    Data(IgnoredAny),
+    End(IgnoredAny),
+    Drop(IgnoredAny),
+    Ack(IgnoredAny),
+    Signal(IgnoredAny),
+}
+
+#[derive(Debug, Deserialize)]
+struct EngineHello {
+    protocol: String,
+    version: String,
+}
+
+#[derive(Debug, Deserialize)]
+enum PluginCall {
+    Metadata,
+    Signature,
+    Run(CallInfo),
+    CustomValueOp(IgnoredAny),
+    GetCompletion(IgnoredAny),
+}
+
+#[derive(Debug, Deserialize)]
+struct CallInfo {
+    name: String,
+    call: EvaluatedCall,
+}
+
+#[derive(Debug, Deserialize)]
+struct EvaluatedCall {
+    head: Span,
+    named: Vec<(Spanned, Option<FlagValue>)>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Spanned {
+    item: String,
+}
+
+// The signatures only accept strings and ints, and nushell checks flags against them
+#[derive(Debug, Deserialize)]
+enum FlagValue {
+    String { val: String },
+    Int { val: i64 },
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+struct Span {
+    start: usize,
+    end: usize,
+}
+
+#[derive(Serialize)]
+enum PluginOutput {
+    Hello(ProtocolInfo),
+    CallResponse(u64, PluginCallResponse),
+}
+
+#[derive(Serialize)]
+struct ProtocolInfo {
+    protocol: String,
+    version: String,
+    features: Vec<String>,
+}
+
+#[derive(Serialize)]
+enum PluginCallResponse {
+    Error(ShellError),
+    Metadata(PluginMetadata),
+    Signature(Vec<PluginSignature>),
+    PipelineData(PipelineDataHeader),
+}
+
+#[derive(Serialize)]
+enum ShellError {
+    LabeledError(LabeledError),
+}
+
+#[derive(Serialize)]
+struct LabeledError {
+    msg: String,
+    labels: Vec<ErrorLabel>,
+    code: Option<String>,
+    url: Option<String>,
+    help: Option<String>,
+    inner: Vec<ShellError>,
+}
+
+#[derive(Serialize)]
+struct ErrorLabel {
+    text: String,
+    span: Span,
+}
+
+#[derive(Serialize)]
+struct PluginMetadata {
+    version: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+enum PipelineDataHeader {
+    // The second field is metadata, which we never have
+    Value(Value, Option<()>),
+}
+
+#[derive(Serialize)]
+enum Value {
+    String { val: String, span: Span },
+    Int { val: i64, span: Span },
+    Record { val: Record, span: Span },
+}
+
+// nushell expects records as maps, in column order
+struct Record(Vec<(&'static str, Value)>);
+
+impl Serialize for Record {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.0.iter().map(|(column, value)| (column, value)))
+    }
+}
+
+#[derive(Serialize)]
+struct PluginSignature {
+    sig: Signature,
+    examples: Vec<()>,
+}
+
+#[derive(Serialize)]
+struct Signature {
+    name: String,
+    description: String,
+    extra_description: String,
+    search_terms: Vec<String>,
+    required_positional: Vec<()>,
+    optional_positional: Vec<()>,
+    rest_positional: Option<()>,
+    named: Vec<Flag>,
+    input_output_types: Vec<(Type, Type)>,
+    allow_variants_without_examples: bool,
+    is_filter: bool,
+    creates_scope: bool,
+    allows_unknown_args: bool,
+    category: Category,
+}
+
+#[derive(Serialize)]
+struct Flag {
+    long: &'static str,
+    short: Option<char>,
+    arg: Option<SyntaxShape>,
+    required: bool,
+    desc: &'static str,
+    var_id: Option<usize>,
+    default_value: Option<()>,
+}
+
+#[derive(Serialize)]
+enum SyntaxShape {
+    String,
+    Int,
+}
+
+#[derive(Serialize)]
+enum Type {
+    Nothing,
+    String,
+    Int,
+    Record(Vec<(&'static str, Type)>),
+}
+
+#[derive(Serialize)]
+enum Category {
+    Strings,
+}
+
+pub fn serve(
+    parser: &mut tree_sitter::Parser,
+    input: impl Read,
+    mut output: impl Write,
+) -> Result<()> {
+    let mut input = BufReader::new(input);
+    output.write_all(&[ENCODING.len() as u8])?;
+    output.write_all(ENCODING)?;
+    output.flush()?;
+
+    while let Some(message) = read_message(&mut input)? {
+        match message {
+            // nushell decides whether our version is compatible with its own
+            PluginInput::Hello(hello) => {
+                debug!("Engine speaks {} {}", hello.protocol, hello.version);
+                write_message(
+                    &mut output,
+                    &PluginOutput::Hello(ProtocolInfo {
+                        protocol: "nu-plugin".to_string(),
+                        version: PROTOCOL_VERSION.to_string(),
+                        features: Vec::new(),
+                    }),
+                )?;
+            }
+            PluginInput::Call(id, call) => {
+                debug!("Call {}: {:?}", id, call);
+                let response = match call {
+                    PluginCall::Metadata => PluginCallResponse::Metadata(PluginMetadata {
+                        version: Some(env!("CARGO_PKG_VERSION")),
+                    }),
+                    PluginCall::Signature => PluginCallResponse::Signature(signatures()),
+                    PluginCall::Run(call_info) => {
+                        let head = call_info.call.head;
+                        match run(parser, call_info) {
+                            Ok(value) => PluginCallResponse::PipelineData(
+                                PipelineDataHeader::Value(value, None),
+                            ),
+                            Err(msg) => error_response(msg, head),
+                        }
+                    }
+                    PluginCall::CustomValueOp(_) | PluginCall::GetCompletion(_) => {
+                        error_response("Not supported by yfnutool".to_string(), Span::default())
+                    }
+                };
+                write_message(&mut output, &PluginOutput::CallResponse(id, response))?;
+            }
+            PluginInput::Goodbye => {
+                debug!("Engine said goodbye, shutting down");
+                return Ok(());
+            }
+            other => debug!("Ignoring {:?}", other),
+        }
+    }
+    debug!("Input closed, shutting down");
+    Ok(())
+}
+
+// None once the input is closed between messages
+fn read_message(input: &mut impl Read) -> Result<Option<PluginInput>> {
+    match rmp_serde::decode::from_read(&mut *input) {
+        Ok(message) => Ok(Some(message)),
+        Err(rmp_serde::decode::Error::InvalidMarkerRead(err))
+            if err.kind() == io::ErrorKind::UnexpectedEof =>
+        {
+            Ok(None)
+        }
+        Err(err) => Err(err).with_context(|| "Unable to decode message from nushell"),
+    }
+}
+
+fn write_message(output: &mut impl Write, message: &PluginOutput) -> Result<()> {
+    rmp_serde::encode::write_named(output, message)?;
+    // nushell waits on each response before carrying on
+    output.flush()?;
+    Ok(())
+}
+
+fn error_response(msg: String, span: Span) -> PluginCallResponse {
+    PluginCallResponse::Error(ShellError::LabeledError(LabeledError {
+        labels: vec![ErrorLabel {
+            text: msg.clone(),
+            span,
+        }],
+        msg,
+        code: None,
+        url: None,
+        help: None,
+        inner: Vec::new(),
+    }))
+}
+
+fn signatures() -> Vec<PluginSignature> {
+    Operation::value_variants()
+        .iter()
+        .filter_map(|operation| operation.to_possible_value())
+        .map(|operation| PluginSignature {
+            sig: Signature {
+                name: format!("{}{}", COMMAND_PREFIX, operation.get_name()),
+                description: operation
+                    .get_help()
+                    .map(|help| help.to_string())
+                    .unwrap_or_default(),
+                extra_description: "Returns the new buffer and cursor, ready for \
+                                    `commandline edit --replace` and `commandline set-cursor`, \
+                                    and the other end of the selection if there is one. \
+                                    Delete, change and yank also return the text they acted on \
+                                    as `yanked`."
+                    .to_string(),
+                search_terms: vec!["commandline".to_string(), "string".to_string()],
+                required_positional: Vec::new(),
+                optional_positional: Vec::new(),
+                rest_positional: None,
+                named: vec![
+                    flag(
+                        "help",
+                        Some('h'),
+                        None,
+                        "Display the help message for this command",
+                    ),
+                    flag(
+                        "text",
+                        Some('t'),
+                        Some(SyntaxShape::String),
+                        "Command line text, as from `commandline`",
+                    ),
+                    flag(
+                        "cursor",
+                        Some('c'),
+                        Some(SyntaxShape::Int),
+                        "Cursor position in graphemes, as from `commandline get-cursor`",
+                    ),
+                    Flag {
+                        required: false,
+                        ..flag(
+                            "selection",
+                            Some('s'),
+                            Some(SyntaxShape::Int),
+                            "The other end of the selection from the cursor, in graphemes",
+                        )
+                    },
+                    Flag {
+                        required: false,
+                        ..flag(
+                            "object",
+                            Some('o'),
+                            Some(SyntaxShape::String),
+                            "Text object for delete, change and yank, like inner-string",
+                        )
+                    },
+                ],
+                input_output_types: vec![(
+                    Type::Nothing,
+                    Type::Record(vec![
+                        ("status", Type::String),
+                        ("buffer", Type::String),
+                        ("cursor", Type::Int),
+                    ]),
+                )],
+                allow_variants_without_examples: true,
+                is_filter: false,
+                creates_scope: false,
+                allows_unknown_args: false,
+                category: Category::Strings,
+            },
+            examples: Vec::new(),
+        })
+        .collect()
+}
+
+fn flag(
+    long: &'static str,
+    short: Option<char>,
+    arg: Option<SyntaxShape>,
+    desc: &'static str,
+) -> Flag {
+    Flag {
+        long,
+        short,
+        // Everything but --help, --selection and --object is needed for the command to do anything
+        required: arg.is_some(),
+        arg,
+        desc,
+        var_id: None,
+        default_value: None,
+    }
+}
+
+fn run(parser: &mut tree_sitter::Parser, call_info: CallInfo) -> Result<Value, String> {
+    let span = call_info.call.head;
+    let operation = call_info
+        .name
+        .strip_prefix(COMMAND_PREFIX)
+        .ok_or_else(|| format!("Unknown command {:?}", call_info.name))?;
+    let flag_value = |name: &str| {
+        call_info
+            .call
+            .named
+            .iter()
+            .find(|(flag, _)| flag.item == name)
+            .and_then(|(_, value)| value.as_ref())
+    };
+    let Some(FlagValue::String { val: buffer }) = flag_value("text") else {
+        return Err("--text is required".to_string());
+    };
+    let Some(FlagValue::Int { val: cursor }) = flag_value("cursor") else {
+        return Err("--cursor is required".to_string());
+    };
+    let cursor = usize::try_from(*cursor)
+        .map_err(|_| format!("--cursor can't be negative, got {}", cursor))?;
+    let selection = match flag_value("selection") {
+        Some(FlagValue::Int { val: selection }) => Some(
+            usize::try_from(*selection)
+                .map_err(|_| format!("--selection can't be negative, got {}", selection))?,
+        ),
+        _ => None,
+    };
+    let text_object = match flag_value("object") {
+        Some(FlagValue::String { val: object }) => Some(object.parse()?),
+        _ => None,
+    };
+    let response = protocol::handle(
+        parser,
+        &protocol::Request {
+            version: protocol::VERSION,
+            operation: operation.to_string(),
+            options: Default::default(),
+            buffer: buffer.as_str().into(),
+            cursor,
+            cursor_point: None,
+            selection,
+            text_object,
+        },
+    );
+    let status = match response.status {
+        protocol::Status::Changed => "changed",
+        protocol::Status::NoOp => "no-op",
+        protocol::Status::Error => {
+            return Err(response
+                .message
+                .unwrap_or_else(|| format!("{} failed", operation)))
+        }
+    };
+    let mut columns = vec![
+        (
+            "status",
+            Value::String {
+                val: status.to_string(),
+                span,
+            },
+        ),
+        (
+            "buffer",
+            Value::String {
+                // Buffers start out as strings here, and operations keep utf-8 valid. Motions
+                // leave the buffer as it was, so they don't send it back.
+                val: response.buffer.map_or_else(
+                    || buffer.clone(),
+                    |buffer| buffer.0.to_str_lossy().into_owned(),
+                ),
+                span,
+            },
+        ),
+        (
+            "cursor",
+            Value::Int {
+                val: response.cursor as i64,
+                span,
+            },
+        ),
+    ];
+    if let Some(selection) = response.selection {
+        columns.push((
+            "selection",
+            Value::Int {
+                val: selection as i64,
+                span,
+            },
+        ));
+    }
+    if let Some(yanked) = response.yanked {
+        columns.push((
+            "yanked",
+            Value::String {
+                val: yanked.0.to_str_lossy().into_owned(),
+                span,
+            },
+        ));
+    }
+    Ok(Value::Record {
+        val: Record(columns),
+        span,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rmpv::Value as Msg;
+
+    // Stands in for the nushell side of the conversation
+
+    fn map(entries: Vec<(&str, Msg)>) -> Msg {
+        Msg::Map(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.into(), value))
+                .collect(),
+        )
+    }
+
+    fn span() -> Msg {
+        map(vec![("start", 0.into()), ("end", 8.into())])
+    }
+
+    fn hello() -> Msg {
+        map(vec![(
+            "Hello",
+            map(vec![
+                ("protocol", "nu-plugin".into()),
+                ("version", "0.106.0".into()),
+                ("features", Msg::Array(Vec::new())),
+            ]),
+        )])
+    }
+
+    fn call(id: u64, call: Msg) -> Msg {
+        map(vec![("Call", Msg::Array(vec![id.into(), call]))])
+    }
+
+    fn run_call(id: u64, name: &str, named: Vec<(&str, Msg)>) -> Msg {
+        let named = named
+            .into_iter()
+            .map(|(flag, value)| {
+                Msg::Array(vec![
+                    map(vec![("item", flag.into()), ("span", span())]),
+                    value,
+                ])
+            })
+            .collect();
+        call(
+            id,
+            map(vec![(
+                "Run",
+                map(vec![
+                    ("name", name.into()),
+                    (
+                        "call",
+                        map(vec![
+                            ("head", span()),
+                            ("positional", Msg::Array(Vec::new())),
+                            ("named", Msg::Array(named)),
+                        ]),
+                    ),
+                    ("input", "Empty".into()),
+                ]),
+            )]),
+        )
+    }
+
+    fn string(val: &str) -> Msg {
+        map(vec![(
+            "String",
+            map(vec![("val", val.into()), ("span", span())]),
+        )])
+    }
+
+    fn int(val: i64) -> Msg {
+        map(vec![(
+            "Int",
+            map(vec![("val", val.into()), ("span", span())]),
+        )])
+    }
+
+    fn converse(messages: Vec<Msg>) -> Vec<Msg> {
+        let mut input = Vec::new();
+        for message in &messages {
+            rmpv::encode::write_value(&mut input, message).unwrap();
+        }
+        let mut output = Vec::new();
+        serve(
+            &mut crate::nu_parser().unwrap(),
+            input.as_slice(),
+            &mut output,
+        )
+        .unwrap();
+        let mut encoding = vec![ENCODING.len() as u8];
+        encoding.extend(ENCODING);
+        let mut output = output
+            .strip_prefix(encoding.as_slice())
+            .expect("Encoding should come first");
+        let mut result = Vec::new();
+        while !output.is_empty() {
+            result.push(rmpv::decode::read_value(&mut output).unwrap());
+        }
+        result
+    }
+
+    fn get<'a>(value: &'a Msg, key: &str) -> &'a Msg {
+        value
+            .as_map()
+            .unwrap_or_else(|| panic!("{} is not a map", value))
+            .iter()
+            .find(|(k, _)| k.as_str() == Some(key))
+            .map(|(_, v)| v)
+            .unwrap_or_else(|| panic!("{} has no {:?}", value, key))
+    }
+
+    // The payload of the response to a call
+    fn response<'a>(message: &'a Msg, id: u64, kind: &str) -> &'a Msg {
+        let call_response = get(message, "CallResponse").as_array().unwrap();
+        pretty_assertions::assert_eq!(call_response[0].as_u64(), Some(id));
+        get(&call_response[1], kind)
+    }
+
+    #[test]
+    fn should_answer_hello_with_own_version() {
+        let messages = converse(vec![hello()]);
+        pretty_assertions::assert_eq!(messages.len(), 1);
+        pretty_assertions::assert_eq!(
+            get(get(&messages[0], "Hello"), "version").as_str(),
+            Some(PROTOCOL_VERSION)
+        );
+    }
+
+    #[test]
+    fn should_have_a_signature_per_operation() {
+        let messages = converse(vec![hello(), call(0, "Signature".into())]);
+        let names: Vec<_> = response(&messages[1], 0, "Signature")
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|signature| get(get(signature, "sig"), "name").as_str().unwrap())
+            .collect();
+        pretty_assertions::assert_eq!(
+            names,
+            vec![
+                "yfnutool interpolate",
+                "yfnutool deinterpolate",
+                "yfnutool cycle-quotes",
+                "yfnutool expand-selection",
+                "yfnutool shrink-selection",
+                "yfnutool next-argument",
+                "yfnutool previous-argument",
+                "yfnutool next-pipe-element",
+                "yfnutool previous-pipe-element",
+                "yfnutool next-statement",
+                "yfnutool previous-statement",
+                "yfnutool matching-bracket",
+                "yfnutool delete",
+                "yfnutool change",
+                "yfnutool yank"
+            ]
+        );
+    }
+
+    #[test]
+    fn should_run_operation() {
+        let messages = converse(vec![
+            hello(),
+            run_call(
+                1,
+                "yfnutool interpolate",
+                vec![("text", string("")), ("cursor", int(0))],
+            ),
+        ]);
+        let value = response(&messages[1], 1, "PipelineData");
+        let record = get(&get(value, "Value").as_array().unwrap()[0], "Record");
+        let column = |name| get(get(record, "val"), name).clone();
+        pretty_assertions::assert_eq!(
+            (column("status"), column("buffer"), column("cursor")),
+            (string("changed"), string(r#"$"()""#), int(3))
+        );
+    }
+
+    #[yare::parameterized(
+        negative_cursor = {vec![("text", string("")), ("cursor", int(-1))]},
+        missing_text = {vec![("cursor", int(0))]},
+    )]
+    fn should_report_bad_flags(named: Vec<(&str, Msg)>) {
+        let messages = converse(vec![hello(), run_call(2, "yfnutool interpolate", named)]);
+        let error = get(response(&messages[1], 2, "Error"), "LabeledError");
+        assert!(get(error, "msg").as_str().is_some());
+    }
+
+    #[test]
+    fn should_stop_at_goodbye() {
+        let messages = converse(vec![hello(), "Goodbye".into(), call(3, "Metadata".into())]);
+        pretty_assertions::assert_eq!(messages.len(), 1);
+    }
+
+    #[test]
+    fn should_name_encoding_before_hello() {
+        let mut output = Vec::new();
+        serve(&mut crate::nu_parser().unwrap(), io::empty(), &mut output).unwrap();
+        pretty_assertions::assert_eq!(output.as_bstr(), b"\x07msgpack".as_bstr());
+    }
+}
@@ -0,0 +1,700 @@
+use crate::cmd_line::{self, Buffer};
+use crate::{syntax, text_object::TextObject, Operation};
+use anyhow::{Context, Result};
+use bstr::{BString, ByteSlice};
+use clap::ValueEnum;
+use log::{debug, error, warn};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Request {
+    pub version: u32,
+    pub operation: String,
+    #[serde(default)]
+    pub options: Options,
+    pub buffer: Text,
+    // From the start of the buffer, in options.cursor_unit
+    #[serde(default)]
+    pub cursor: usize,
+    // Takes the place of cursor when present, with the column counted in options.column. The
+    // response then carries its cursor this way too.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cursor_point: Option<cmd_line::Point>,
+    // The other end of the selection from the cursor, counted like cursor (in options.cursor_unit
+    // even alongside a cursor_point)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub selection: Option<usize>,
+    // What delete, change and yank act on, named like inner-string
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text_object: Option<TextObject>,
+}
+
+// An empty request in the current version, to fill in with struct update syntax
+impl Default for Request {
+    fn default() -> Self {
+        Request {
+            version: VERSION,
+            operation: String::new(),
+            options: Options::default(),
+            buffer: Text::default(),
+            cursor: 0,
+            cursor_point: None,
+            selection: None,
+            text_object: None,
+        }
+    }
+}
+
+// Knobs that apply to every operation. Anything we don't know about is ignored.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Options {
+    pub response: ResponseMode,
+    // How the cursor is counted, both in the request and in the response
+    pub cursor_unit: cmd_line::Unit,
+    // Which way to move a cursor that the operation leaves inside a grapheme
+    pub snap: cmd_line::Snap,
+    // How the columns of cursor_point are counted
+    pub column: cmd_line::Column,
+}
+
+// What a response carries to describe the new command line
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResponseMode {
+    // The whole buffer
+    #[default]
+    Buffer,
+    // Just the edits that turn the old buffer into the new one
+    Edits,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct Response {
+    pub version: u32,
+    pub status: Status,
+    // Something to show the user, if there's anything worth saying
+    pub message: Option<String>,
+    // Exactly one of buffer or edits is present, depending on the requested ResponseMode. Neither
+    // is for motions, which leave the text alone.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub buffer: Option<Text>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edits: Option<Vec<TextEdit>>,
+    pub cursor: usize,
+    // Only present when the request had a cursor_point
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cursor_point: Option<cmd_line::Point>,
+    // Only present when something is selected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub selection: Option<usize>,
+    // The text a text object operation covered, when there was an object at the cursor
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub yanked: Option<Text>,
+}
+
+// Replaces the utf-8 bytes from start to end, as the buffer was after the previous edit
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct TextEdit {
+    pub start: usize,
+    pub end: usize,
+    pub replacement: Text,
+}
+
+// Command line text, which needn't be valid utf-8. Goes over the wire as a msgpack str when it is
+// and as bin when it isn't, and either is accepted coming in.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Text(pub BString);
+
+impl Serialize for Text {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self.0.to_str() {
+            Ok(str) => serializer.serialize_str(str),
+            Err(_) => serializer.serialize_bytes(&self.0),
+        }
+    }
+}
+impl<'de> Deserialize<'de> for Text {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        BString::deserialize(deserializer).map(Text)
+    }
+}
+impl From<&str> for Text {
+    fn from(value: &str) -> Self {
+        Text(value.into())
+    }
+}
+impl From<String> for Text {
+    fn from(value: String) -> Self {
+        Text(value.into())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+    Changed,
+    NoOp,
+    Error,
+}
+
+impl Request {
+    fn cmd_line(&self) -> Result<cmd_line::Bytes> {
+        let (cursor, unit) = match self.cursor_point {
+            Some(point) => {
+                let mut bytes = cmd_line::Bytes::with_cursor_in(
+                    self.buffer.0.clone(),
+                    0,
+                    cmd_line::Unit::Byte,
+                )?;
+                bytes.set_cursor_at(point, self.options.column)?;
+                (bytes.cursor_pos(), cmd_line::Unit::Byte)
+            }
+            None => (self.cursor, self.options.cursor_unit),
+        };
+        let mut bytes = self.with_cursor_in(cursor, unit)?;
+        if let Some(selection) = self.selection {
+            let anchor = self
+                .with_cursor_in(selection, self.options.cursor_unit)
+                .with_context(|| "When placing the selection")?
+                .cursor_pos();
+            bytes.set_anchor(
+                cmd_line::AnchorName::Selection,
+                anchor,
+                cmd_line::Gravity::Left,
+            );
+        }
+        Ok(bytes)
+    }
+
+    // Valid utf-8 counts graphemes the way Utf8 does, so those agree with what the operations see
+    fn with_cursor_in(&self, cursor: usize, unit: cmd_line::Unit) -> Result<cmd_line::Bytes> {
+        let text = &self.buffer.0;
+        match text.to_str() {
+            Ok(str) => Ok(cmd_line::Utf8::with_cursor_in(str.to_string(), cursor, unit)?.into()),
+            Err(_) => cmd_line::Bytes::with_cursor_in(text.clone(), cursor, unit),
+        }
+    }
+
+    // Where `output` leaves the cursor, counted the way the request counted its own. A cursor that
+    // the operation left inside a grapheme moves out of it the way options.snap says.
+    fn response_cursor(
+        &self,
+        operation: Operation,
+        output: &mut cmd_line::Bytes,
+    ) -> Result<(usize, Option<cmd_line::Point>)> {
+        let (unit, snap) = (self.options.cursor_unit, self.options.snap);
+        if output.snap_cursor(snap) {
+            warn!(
+                "{:?} left the cursor inside a grapheme, snapped {:?}",
+                operation, snap
+            );
+        }
+        let cursor = output.cursor_in(unit);
+        let cursor_point = self
+            .cursor_point
+            .map(|_| output.cursor_point(self.options.column));
+        Ok((cursor, cursor_point))
+    }
+
+    // Where `output` leaves the other end of the selection, counted and snapped like the cursor
+    fn response_selection(&self, output: &cmd_line::Bytes) -> Result<Option<usize>> {
+        let Some(anchor) = output.anchor(cmd_line::AnchorName::Selection) else {
+            return Ok(None);
+        };
+        let mut at_anchor = output.clone();
+        at_anchor.set_cursor_pos(anchor);
+        let (unit, snap) = (self.options.cursor_unit, self.options.snap);
+        at_anchor.snap_cursor(snap);
+        Ok(Some(at_anchor.cursor_in(unit)))
+    }
+}
+
+impl Response {
+    // Hands back the request's buffer, cursor and selection as they were
+    fn error(request: &Request, message: String) -> Self {
+        Response {
+            message: Some(message),
+            selection: request.selection,
+            ..Self::new(
+                request,
+                Status::Error,
+                request.buffer.clone(),
+                (request.cursor, request.cursor_point),
+                Vec::new(),
+            )
+        }
+    }
+
+    // For bytes that didn't decode to a request, so there's no buffer or cursor to hand back
+    pub fn undecodable(message: String) -> Self {
+        Response {
+            version: VERSION,
+            status: Status::Error,
+            message: Some(message),
+            buffer: None,
+            edits: None,
+            cursor: 0,
+            cursor_point: None,
+            selection: None,
+            yanked: None,
+        }
+    }
+
+    fn new(
+        request: &Request,
+        status: Status,
+        buffer: Text,
+        (cursor, cursor_point): (usize, Option<cmd_line::Point>),
+        edits: Vec<TextEdit>,
+    ) -> Self {
+        let (buffer, edits) = match request.options.response {
+            ResponseMode::Buffer => (Some(buffer), None),
+            ResponseMode::Edits => (None, Some(edits)),
+        };
+        Response {
+            version: VERSION,
+            status,
+            message: None,
+            buffer,
+            edits,
+            cursor,
+            cursor_point,
+            selection: None,
+            yanked: None,
+        }
+    }
+}
+
+// Never fails: anything that goes wrong comes back as an error response carrying the request's
+// buffer and cursor, so the caller always has something well-formed to decode
+pub fn handle(parser: &mut tree_sitter::Parser, request: &Request) -> Response {
+    handle_reusing(parser, request, &mut None)
+}
+
+// Parses starting from the tree of the command line handled before, which is usually the same
+// one a keystroke or so ago, and leaves this one's tree behind for next time
+pub fn handle_reusing(
+    parser: &mut tree_sitter::Parser,
+    request: &Request,
+    previous: &mut Option<syntax::Previous>,
+) -> Response {
+    if request.version != VERSION {
+        return Response::error(
+            request,
+            format!(
+                "Unsupported protocol version {} (expected {})",
+                request.version, VERSION
+            ),
+        );
+    }
+    let Ok(operation) = Operation::from_str(&request.operation, false) else {
+        return Response::error(
+            request,
+            format!("Unknown operation {:?}", request.operation),
+        );
+    };
+    let input = match request.cmd_line() {
+        Ok(input) => input,
+        Err(err) => return Response::error(request, format!("{:#}", err)),
+    };
+    debug!("Running {:?}", operation);
+    let (input_cursor, input_selection, at) = (
+        input.cursor_pos(),
+        input.anchor(cmd_line::AnchorName::Selection),
+        input.cursor_point(cmd_line::Column::Grapheme),
+    );
+    let input = syntax::Parsed::reusing(input, previous.take());
+    let result =
+        crate::run(parser, operation, input, request.text_object).and_then(|(output, taken)| {
+            *previous = output.previous();
+            let yanked = taken.map(Text);
+            let mut output = output.buffer;
+            let cursor = request.response_cursor(operation, &mut output)?;
+            let selection = request.response_selection(&output)?;
+            let edits = std::mem::take(&mut output.edits);
+            if output.text == request.buffer.0
+                && output.cursor_pos() == input_cursor
+                && output.anchor(cmd_line::AnchorName::Selection) == input_selection
+            {
+                return Ok(Response {
+                    selection,
+                    yanked,
+                    ..Response::new(request, Status::NoOp, Text(output.text), cursor, Vec::new())
+                });
+            }
+            let edits = text_edits(&request.buffer.0, &output.text, edits);
+            Ok(Response {
+                selection,
+                yanked,
+                ..Response::new(request, Status::Changed, Text(output.text), cursor, edits)
+            })
+        });
+    match result {
+        Ok(response) if operation.is_motion() => Response {
+            buffer: None,
+            edits: None,
+            ..response
+        },
+        Ok(response) => response,
+        Err(err) => {
+            error!("{:?} failed at {}: {:#}", operation, at, err);
+            Response::error(
+                request,
+                format!("{:?} failed at {}: {:#}", operation, at, err),
+            )
+        }
+    }
+}
+
+// Operations that build their result from scratch rather than editing their input don't leave a
+// usable trail, so those become a single edit of the whole buffer
+fn text_edits(before: &[u8], after: &[u8], edits: Vec<cmd_line::Edit>) -> Vec<TextEdit> {
+    let edits = if cmd_line::replay(before, &edits) == after {
+        edits
+    } else {
+        vec![cmd_line::Edit {
+            range: 0..before.len(),
+            replacement: after.into(),
+        }]
+    };
+    edits
+        .into_iter()
+        .map(|edit| TextEdit {
+            start: edit.range.start,
+            end: edit.range.end,
+            replacement: Text(edit.replacement),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yare::parameterized;
+
+    fn request(operation: &str, buffer: &str, cursor: usize) -> Request {
+        Request {
+            operation: operation.to_string(),
+            buffer: buffer.into(),
+            cursor,
+            ..Request::default()
+        }
+    }
+
+    #[parameterized(
+        changed = {request("interpolate", "", 0), Status::Changed, r#"$"()""#, 3},
+        no_op = {request("deinterpolate", "", 0), Status::NoOp, "", 0},
+        unknown_operation = {request("frobnicate", "foo", 1), Status::Error, "foo", 1},
+        unknown_version = {Request { version: VERSION + 1, ..request("interpolate", "foo", 1) }, Status::Error, "foo", 1},
+    )]
+    fn should_respond(request: Request, status: Status, buffer: &str, cursor: usize) {
+        let response = handle(&mut crate::nu_parser().unwrap(), &request);
+        pretty_assertions::assert_eq!(
+            (response.status, response.buffer, response.cursor),
+            (status, Some(buffer.into()), cursor)
+        );
+        pretty_assertions::assert_eq!(response.message.is_some(), status == Status::Error);
+    }
+
+    #[parameterized(
+        grapheme = {Request { options: Options { cursor_unit: cmd_line::Unit::Grapheme, ..Options::default() }, ..request("interpolate", "echo '🍳é'", 8) }, 10},
+        byte = {Request { options: Options { cursor_unit: cmd_line::Unit::Byte, ..Options::default() }, ..request("interpolate", "echo '🍳é'", 12) }, 14},
+        char = {Request { options: Options { cursor_unit: cmd_line::Unit::Char, ..Options::default() }, ..request("interpolate", "echo '🍳é'", 8) }, 10},
+        utf16 = {Request { options: Options { cursor_unit: cmd_line::Unit::Utf16, ..Options::default() }, ..request("interpolate", "echo '🍳é'", 9) }, 11},
+    )]
+    fn should_count_cursor_in_requested_unit(request: Request, cursor: usize) {
+        let response = handle(&mut crate::nu_parser().unwrap(), &request);
+        pretty_assertions::assert_eq!(
+            (response.status, response.buffer, response.cursor),
+            (Status::Changed, Some("echo $'🍳é()'".into()), cursor)
+        );
+    }
+
+    #[test]
+    fn should_reject_cursor_inside_character() {
+        let response = handle(
+            &mut crate::nu_parser().unwrap(),
+            &Request {
+                options: Options {
+                    cursor_unit: cmd_line::Unit::Byte,
+                    ..Options::default()
+                },
+                ..request("interpolate", "echo '🍳'", 7)
+            },
+        );
+        pretty_assertions::assert_eq!(
+            (response.status, response.buffer, response.cursor),
+            (Status::Error, Some("echo '🍳'".into()), 7)
+        );
+    }
+
+    #[parameterized(
+        empty_buffer = {Request { options: Options { response: ResponseMode::Edits, ..Options::default() }, ..request("interpolate", "", 0) }, r#"$"()""#, 3},
+        single_quote = {Request { options: Options { response: ResponseMode::Edits, ..Options::default() }, ..request("interpolate", "echo 'hi'", 7) }, "echo $'h()i'", 9},
+        no_op = {Request { options: Options { response: ResponseMode::Edits, ..Options::default() }, ..request("deinterpolate", "echo 'hi'", 7) }, "echo 'hi'", 7},
+        error = {Request { options: Options { response: ResponseMode::Edits, ..Options::default() }, ..request("frobnicate", "echo 'hi'", 7) }, "echo 'hi'", 7},
+    )]
+    fn should_respond_with_edits(request: Request, buffer: &str, cursor: usize) {
+        let response = handle(&mut crate::nu_parser().unwrap(), &request);
+        pretty_assertions::assert_eq!(response.buffer, None);
+        let mut edited = request.buffer.0.clone();
+        for edit in response.edits.unwrap() {
+            edited.splice(edit.start..edit.end, Vec::from(edit.replacement.0));
+        }
+        pretty_assertions::assert_eq!((edited.as_bstr(), response.cursor), (buffer.into(), cursor));
+    }
+
+    #[parameterized(
+        expand = {Request { selection: Some(5), ..request("expand-selection", "echo foo bar", 8) }, Status::Changed, 12, Some(0)},
+        kept_by_other_operations = {Request { selection: Some(5), ..request("deinterpolate", "echo foo", 8) }, Status::NoOp, 8, Some(5)},
+        error = {Request { selection: Some(5), ..request("frobnicate", "echo foo", 8) }, Status::Error, 8, Some(5)},
+        new_selection = {request("expand-selection", "echo foo", 6), Status::Changed, 8, Some(5)},
+    )]
+    fn should_respond_with_selection(
+        request: Request,
+        status: Status,
+        cursor: usize,
+        selection: Option<usize>,
+    ) {
+        let response = handle(&mut crate::nu_parser().unwrap(), &request);
+        pretty_assertions::assert_eq!(
+            (response.status, response.cursor, response.selection),
+            (status, cursor, selection)
+        );
+    }
+
+    #[parameterized(
+        buffer = {request("matching-bracket", "[1, 2]", 0)},
+        edits = {Request { options: Options { response: ResponseMode::Edits, ..Options::default() }, ..request("matching-bracket", "[1, 2]", 0) }},
+    )]
+    fn should_only_move_cursor_for_motions(request: Request) {
+        let response = handle(&mut crate::nu_parser().unwrap(), &request);
+        pretty_assertions::assert_eq!(
+            (
+                response.status,
+                response.buffer,
+                response.edits,
+                response.cursor
+            ),
+            (Status::Changed, None, None, 5)
+        );
+    }
+
+    #[parameterized(
+        delete = {Request { text_object: Some("around-brackets".parse().unwrap()), ..request("delete", "[1, 2]", 2) }, Status::Changed, "", 0, Some("[1, 2]")},
+        yank = {Request { text_object: Some("inner-brackets".parse().unwrap()), ..request("yank", "[1, 2]", 2) }, Status::Changed, "[1, 2]", 1, Some("1, 2")},
+        yank_in_place = {Request { text_object: Some("inner-brackets".parse().unwrap()), ..request("yank", "[1, 2]", 1) }, Status::NoOp, "[1, 2]", 1, Some("1, 2")},
+        nothing_there = {Request { text_object: Some("inner-brackets".parse().unwrap()), ..request("delete", "1", 0) }, Status::NoOp, "1", 0, None},
+        no_text_object = {request("delete", "[1, 2]", 2), Status::Error, "[1, 2]", 2, None},
+    )]
+    fn should_respond_with_yanked_text(
+        request: Request,
+        status: Status,
+        buffer: &str,
+        cursor: usize,
+        yanked: Option<&str>,
+    ) {
+        let response = handle(&mut crate::nu_parser().unwrap(), &request);
+        pretty_assertions::assert_eq!(
+            (
+                response.status,
+                response.buffer,
+                response.cursor,
+                response.yanked
+            ),
+            (status, Some(buffer.into()), cursor, yanked.map(Text::from))
+        );
+    }
+
+    #[test]
+    fn should_keep_recorded_edits() {
+        let edits = vec![
+            cmd_line::Edit {
+                range: 5..5,
+                replacement: "$".into(),
+            },
+            cmd_line::Edit {
+                range: 8..8,
+                replacement: "()".into(),
+            },
+        ];
+        pretty_assertions::assert_eq!(
+            text_edits(b"echo 'hi'", b"echo $'h()i'", edits),
+            vec![
+                TextEdit {
+                    start: 5,
+                    end: 5,
+                    replacement: "$".into()
+                },
+                TextEdit {
+                    start: 8,
+                    end: 8,
+                    replacement: "()".into()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn should_replace_whole_buffer_when_edits_dont_add_up() {
+        pretty_assertions::assert_eq!(
+            text_edits(b"foo", b"bar", Vec::new()),
+            vec![TextEdit {
+                start: 0,
+                end: 3,
+                replacement: "bar".into()
+            }]
+        );
+    }
+
+    #[test]
+    fn should_keep_buffer_when_operation_fails() {
+        // Without a grammar, anything that needs parsing fails
+        let response = handle(
+            &mut tree_sitter::Parser::new(),
+            &request("interpolate", "echo foo", 7),
+        );
+        pretty_assertions::assert_eq!(
+            (response.status, response.buffer, response.cursor),
+            (Status::Error, Some("echo foo".into()), 7)
+        );
+        let message = response.message.unwrap();
+        assert!(message.contains("parse"));
+        assert!(message.contains("at 1:8"), "{}", message);
+    }
+
+    #[test]
+    fn should_take_cursor_as_point() {
+        let response = handle(
+            &mut crate::nu_parser().unwrap(),
+            &Request {
+                options: Options {
+                    column: cmd_line::Column::Width,
+                    ..Options::default()
+                },
+                cursor_point: Some(cmd_line::Point { line: 1, column: 8 }),
+                ..request("interpolate", "ls\r\necho '🍳'", 0)
+            },
+        );
+        pretty_assertions::assert_eq!(
+            (
+                response.status,
+                response.buffer,
+                response.cursor,
+                response.cursor_point
+            ),
+            (
+                Status::Changed,
+                Some("ls\r\necho $'🍳()'".into()),
+                12,
+                Some(cmd_line::Point {
+                    line: 1,
+                    column: 10
+                })
+            )
+        );
+    }
+
+    #[parameterized(
+        no_op = {Request { options: Options { column: cmd_line::Column::Byte, ..Options::default() }, cursor_point: Some(cmd_line::Point { line: 0, column: 0 }), ..request("deinterpolate", "", 0) }, Status::NoOp},
+        past_end_of_line = {Request { options: Options { column: cmd_line::Column::Byte, ..Options::default() }, cursor_point: Some(cmd_line::Point { line: 0, column: 2 }), ..request("deinterpolate", "a\nb", 0) }, Status::Error},
+        inside_wide_char = {Request { options: Options { column: cmd_line::Column::Width, ..Options::default() }, cursor_point: Some(cmd_line::Point { line: 0, column: 1 }), ..request("deinterpolate", "🍳", 0) }, Status::Error},
+    )]
+    fn should_answer_point_with_point(request: Request, status: Status) {
+        let response = handle(&mut crate::nu_parser().unwrap(), &request);
+        pretty_assertions::assert_eq!(
+            (response.status, response.cursor_point),
+            (status, request.cursor_point)
+        );
+    }
+
+    #[test]
+    fn should_decode_request_without_options() {
+        #[derive(Serialize)]
+        struct Minimal<'a> {
+            version: u32,
+            operation: &'a str,
+            buffer: &'a str,
+            cursor: usize,
+        }
+        let encoded = rmp_serde::encode::to_vec_named(&Minimal {
+            version: VERSION,
+            operation: "interpolate",
+            buffer: "foo",
+            cursor: 1,
+        })
+        .unwrap();
+        let decoded: Request = rmp_serde::decode::from_slice(&encoded).unwrap();
+        pretty_assertions::assert_eq!(decoded.buffer, "foo".into());
+    }
+
+    #[parameterized(
+        str = {rmpv::Value::from("foo"), b"foo"},
+        bin = {rmpv::Value::from(&b"\xfffoo"[..]), b"\xfffoo"},
+    )]
+    fn should_decode_buffer(encoded: rmpv::Value, buffer: &[u8]) {
+        let mut bytes = Vec::new();
+        rmpv::encode::write_value(&mut bytes, &encoded).unwrap();
+        let decoded: Text = rmp_serde::decode::from_slice(&bytes).unwrap();
+        pretty_assertions::assert_eq!(decoded.0, buffer);
+    }
+
+    #[parameterized(
+        utf8 = {b"foo", rmpv::Value::from("foo")},
+        not_utf8 = {b"\xc4\x01\xff", rmpv::Value::from(&b"\xc4\x01\xff"[..])},
+    )]
+    fn should_encode_buffer_as_str_when_possible(buffer: &[u8], expected: rmpv::Value) {
+        let encoded = rmp_serde::encode::to_vec(&Text(buffer.into())).unwrap();
+        pretty_assertions::assert_eq!(
+            rmpv::decode::read_value(&mut encoded.as_slice()).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn should_keep_bytes_that_arent_utf8() {
+        let response = handle(
+            &mut crate::nu_parser().unwrap(),
+            &Request {
+                buffer: Text(b"echo '\xff'"[..].into()),
+                ..request("interpolate", "", 7)
+            },
+        );
+        pretty_assertions::assert_eq!(
+            (response.status, response.buffer, response.cursor),
+            (Status::Changed, Some(Text(b"echo $'\xff()'"[..].into())), 9)
+        );
+    }
+
+    #[test]
+    fn should_reject_cursor_inside_invalid_sequence() {
+        let response = handle(
+            &mut tree_sitter::Parser::new(),
+            &Request {
+                options: Options {
+                    cursor_unit: cmd_line::Unit::Byte,
+                    ..Options::default()
+                },
+                buffer: Text(b"echo \xf0\x9f"[..].into()),
+                ..request("interpolate", "", 6)
+            },
+        );
+        pretty_assertions::assert_eq!(response.status, Status::Error);
+    }
+
+    #[test]
+    fn should_encode_status_as_string() {
+        let encoded = rmp_serde::encode::to_vec_named(&Response::new(
+            &request("deinterpolate", "", 0),
+            Status::NoOp,
+            "".into(),
+            (0, None),
+            Vec::new(),
+        ))
+        .unwrap();
+        assert!(encoded.windows(5).any(|window| window == b"no-op"));
+    }
+}
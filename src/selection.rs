@@ -0,0 +1,161 @@
+use crate::cmd_line::{AnchorName, Buffer, Gravity};
+use crate::context;
+use crate::syntax::{Parsed, Recovered};
+use anyhow::{Context, Result};
+use log::debug;
+use std::ops::Range;
+use tree_sitter::Node;
+
+// Grows the selection to the smallest named node that's bigger than it. With nothing selected
+// yet, that's the node at the cursor.
+pub fn expand_selection_cli<B: Buffer>(
+    parser: &mut tree_sitter::Parser,
+    mut input: Parsed<B>,
+) -> Result<Parsed<B>> {
+    let recovered = input.recovered_tree(parser)?;
+    let selected = selection(&input);
+    let innermost = if selected.is_empty() {
+        context::classify(&recovered, &input)?.node
+    } else {
+        node_around(&recovered, &selected)?
+    };
+    let Some(range) = context::ancestors(innermost)
+        .map(|node| recovered.range(node))
+        .find(|range| contains(range, &selected) && *range != selected)
+    else {
+        debug!("Nothing bigger to select");
+        return Ok(input);
+    };
+    select(&mut input, range);
+    Ok(input)
+}
+
+// Narrows the selection to the first or last named node inside it, whichever end the cursor is
+// on, undoing expand_selection_cli one step at a time
+pub fn shrink_selection_cli<B: Buffer>(
+    parser: &mut tree_sitter::Parser,
+    mut input: Parsed<B>,
+) -> Result<Parsed<B>> {
+    let selected = selection(&input);
+    if selected.is_empty() {
+        debug!("Nothing selected");
+        return Ok(input);
+    }
+    let recovered = input.recovered_tree(parser)?;
+    let cursor_at_start = input.cursor_pos() == selected.start;
+    let mut node = node_around(&recovered, &selected)?;
+    // Nodes that take up the whole selection are what's selected already
+    let range = loop {
+        let mut cursor = node.walk();
+        let inside: Vec<Node> = node
+            .named_children(&mut cursor)
+            .filter(|child| {
+                let range = recovered.range(*child);
+                !range.is_empty() && contains(&selected, &range)
+            })
+            .collect();
+        let Some(&child) = (if cursor_at_start {
+            inside.first()
+        } else {
+            inside.last()
+        }) else {
+            debug!("Nothing smaller to select");
+            return Ok(input);
+        };
+        let range = recovered.range(child);
+        if range != selected {
+            break range;
+        }
+        node = child;
+    };
+    select(&mut input, range);
+    Ok(input)
+}
+
+// From the cursor to the selection anchor, or empty at the cursor if there's no anchor
+fn selection(input: &impl Buffer) -> Range<usize> {
+    let cursor = input.cursor_pos();
+    let anchor = input.anchor(AnchorName::Selection).unwrap_or(cursor);
+    cursor.min(anchor)..cursor.max(anchor)
+}
+
+// The cursor stays on the end of the selection it was on, which is the end for a new selection
+fn select(input: &mut impl Buffer, range: Range<usize>) {
+    let cursor_at_start = input
+        .anchor(AnchorName::Selection)
+        .is_some_and(|anchor| input.cursor_pos() < anchor);
+    let (cursor, anchor) = if cursor_at_start {
+        (range.start, range.end)
+    } else {
+        (range.end, range.start)
+    };
+    input.set_cursor_pos(cursor);
+    input.set_anchor(AnchorName::Selection, anchor, Gravity::Left);
+}
+
+fn node_around<'tree>(recovered: &'tree Recovered, range: &Range<usize>) -> Result<Node<'tree>> {
+    recovered
+        .tree
+        .root_node()
+        .named_descendant_for_byte_range(range.start, range.end)
+        .with_context(|| format!("Unable to find node around {:?}", range))
+}
+
+fn contains(outer: &Range<usize>, inner: &Range<usize>) -> bool {
+    outer.start <= inner.start && inner.end <= outer.end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd_line::{str_repr, Rope, ToStrRepr};
+    use crate::Operation;
+    use yare::parameterized;
+
+    fn run(operation: Operation, before: &str, anchor: Option<usize>) -> (String, Option<usize>) {
+        let mut before: Rope = str_repr(before);
+        if let Some(anchor) = anchor {
+            before.set_anchor(AnchorName::Selection, anchor, Gravity::Left);
+        }
+        let after = crate::on_every_backend(operation, before);
+        (after.to_str_repr(), after.anchor(AnchorName::Selection))
+    }
+
+    #[parameterized(
+        bare_word = {"echo fo|o bar", None, "echo foo| bar", Some(5)},
+        argument_to_command = {"echo foo| bar", Some(5), "echo foo bar|", Some(0)},
+        keeps_cursor_at_start = {"echo |foo bar", Some(8), "|echo foo bar", Some(12)},
+        command_name_to_command = {"ls | where| size > 1kb", Some(5), "ls | where size > 1kb|", Some(5)},
+        cursor_at_end = {"ls foo|", None, "ls foo|", Some(3)},
+        whole_command_line = {"ls|", Some(0), "ls|", Some(0)},
+    )]
+    fn should_expand(
+        before: &str,
+        anchor: Option<usize>,
+        after: &str,
+        after_anchor: Option<usize>,
+    ) {
+        pretty_assertions::assert_eq!(
+            run(Operation::ExpandSelection, before, anchor),
+            (after.to_string(), after_anchor)
+        );
+    }
+
+    #[parameterized(
+        command_to_last_argument = {"echo foo bar|", Some(0), "echo foo bar|", Some(9)},
+        command_to_name = {"|echo foo bar", Some(12), "|echo foo bar", Some(4)},
+        nothing_selected = {"echo fo|o", None, "echo fo|o", None},
+        nothing_smaller = {"echo foo|", Some(5), "echo foo|", Some(5)},
+    )]
+    fn should_shrink(
+        before: &str,
+        anchor: Option<usize>,
+        after: &str,
+        after_anchor: Option<usize>,
+    ) {
+        pretty_assertions::assert_eq!(
+            run(Operation::ShrinkSelection, before, anchor),
+            (after.to_string(), after_anchor)
+        );
+    }
+}
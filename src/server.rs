@@ -0,0 +1,206 @@
+use crate::protocol::{self, Request, Response};
+use anyhow::{anyhow, Context, Result};
+use log::{debug, warn};
+use std::io::{self, Read, Write};
+
+// Requests and responses are each framed as a big-endian u32 byte length followed by that many
+// bytes of msgpack holding a protocol::Request or protocol::Response.
+// A frame that doesn't decode gets an error response like any other failed request.
+pub fn serve(
+    parser: &mut tree_sitter::Parser,
+    mut input: impl Read,
+    mut output: impl Write,
+) -> Result<()> {
+    // Consecutive requests tend to be the same command line a keystroke apart
+    let mut previous = None;
+    while let Some(frame) = read_frame(&mut input)? {
+        let response = match rmp_serde::decode::from_slice::<Request>(&frame) {
+            Ok(request) => protocol::handle_reusing(parser, &request, &mut previous),
+            Err(err) => {
+                warn!("Unable to decode request: {}", err);
+                Response::undecodable(format!("Unable to decode request: {}", err))
+            }
+        };
+        write_frame(&mut output, &rmp_serde::encode::to_vec_named(&response)?)?;
+    }
+    debug!("Input closed, shutting down");
+    Ok(())
+}
+
+// Command lines run to kilobytes at most, so a length past this is a peer that's lost track of the
+// framing, not a request worth allocating for
+const MAX_FRAME_LEN: usize = 16 << 20;
+
+// None once the input is closed between frames. Closing it anywhere else, even partway through
+// the length, is an UnexpectedEof error.
+fn read_frame(input: &mut impl Read) -> Result<Option<Vec<u8>>> {
+    let mut len_bytes = [0; 4];
+    loop {
+        match input.read(&mut len_bytes[..1]) {
+            Ok(0) => return Ok(None),
+            Ok(_) => break,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err).with_context(|| "Unable to read request length"),
+        }
+    }
+    input
+        .read_exact(&mut len_bytes[1..])
+        .with_context(|| "Unable to read request length")?;
+    let len = u32::from_be_bytes(len_bytes) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(anyhow!(
+            "Request of {} bytes is over the limit of {}",
+            len,
+            MAX_FRAME_LEN
+        ));
+    }
+    let mut frame = vec![0; len];
+    input
+        .read_exact(&mut frame)
+        .with_context(|| "Unable to read request")?;
+    Ok(Some(frame))
+}
+
+fn write_frame(output: &mut impl Write, frame: &[u8]) -> Result<()> {
+    let len = u32::try_from(frame.len()).with_context(|| "Response too large to frame")?;
+    output.write_all(&len.to_be_bytes())?;
+    output.write_all(frame)?;
+    // The other end is waiting on this response before it sends anything else
+    output.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn frames(requests: &[(&str, usize, &str)]) -> Vec<u8> {
+        let mut result = Vec::new();
+        for (operation, cursor, buffer) in requests {
+            let request = Request {
+                operation: operation.to_string(),
+                buffer: (*buffer).into(),
+                cursor: *cursor,
+                ..Request::default()
+            };
+            write_frame(
+                &mut result,
+                &rmp_serde::encode::to_vec_named(&request).unwrap(),
+            )
+            .unwrap();
+        }
+        result
+    }
+
+    fn responses(
+        requests: &[(&str, usize, &str)],
+    ) -> Vec<(protocol::Status, usize, protocol::Text)> {
+        let mut output = Vec::new();
+        serve(
+            &mut crate::nu_parser().unwrap(),
+            frames(requests).as_slice(),
+            &mut output,
+        )
+        .unwrap();
+        let mut output = output.as_slice();
+        let mut result = Vec::new();
+        while let Some(frame) = read_frame(&mut output).unwrap() {
+            let response: Response = rmp_serde::decode::from_slice(&frame).unwrap();
+            result.push((response.status, response.cursor, response.buffer.unwrap()));
+        }
+        result
+    }
+
+    #[test]
+    fn should_answer_every_request() {
+        pretty_assertions::assert_eq!(
+            responses(&[("interpolate", 0, ""), ("deinterpolate", 0, "")]),
+            vec![
+                (protocol::Status::Changed, 3, r#"$"()""#.into()),
+                (protocol::Status::NoOp, 0, "".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn should_keep_serving_after_unknown_operation() {
+        pretty_assertions::assert_eq!(
+            responses(&[("frobnicate", 0, ""), ("interpolate", 0, "")]),
+            vec![
+                (protocol::Status::Error, 0, "".into()),
+                (protocol::Status::Changed, 3, r#"$"()""#.into())
+            ]
+        );
+    }
+
+    #[test]
+    fn should_fail_on_truncated_request() {
+        let mut input = frames(&[("interpolate", 0, "")]);
+        input.pop();
+        let result = serve(
+            &mut crate::nu_parser().unwrap(),
+            input.as_slice(),
+            Vec::new(),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn should_keep_serving_after_undecodable_request() {
+        let mut input = Vec::new();
+        write_frame(&mut input, b"\xc1").unwrap();
+        input.extend(frames(&[("interpolate", 0, "")]));
+        let mut output = Vec::new();
+        serve(
+            &mut crate::nu_parser().unwrap(),
+            input.as_slice(),
+            &mut output,
+        )
+        .unwrap();
+        let mut output = output.as_slice();
+        let mut statuses = Vec::new();
+        while let Some(frame) = read_frame(&mut output).unwrap() {
+            let response: Response = rmp_serde::decode::from_slice(&frame).unwrap();
+            statuses.push((response.status, response.message.is_some()));
+        }
+        pretty_assertions::assert_eq!(
+            statuses,
+            vec![
+                (protocol::Status::Error, true),
+                (protocol::Status::Changed, false)
+            ]
+        );
+    }
+
+    #[test]
+    fn should_fail_on_truncated_length() {
+        let err = read_frame(&mut [0, 0].as_slice()).unwrap_err();
+        pretty_assertions::assert_eq!(
+            err.downcast_ref::<io::Error>().map(io::Error::kind),
+            Some(io::ErrorKind::UnexpectedEof)
+        );
+    }
+
+    #[test]
+    fn should_refuse_oversized_frame() {
+        let input = u32::MAX.to_be_bytes();
+        assert!(read_frame(&mut input.as_slice()).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn roundtrip_frames(messages in proptest::collection::vec(any::<Vec<u8>>(), 0..4)) {
+            let mut buffer = Vec::new();
+            for message in &messages {
+                write_frame(&mut buffer, message).unwrap();
+            }
+            let mut input = buffer.as_slice();
+            let mut read = Vec::new();
+            while let Some(frame) = read_frame(&mut input).unwrap() {
+                read.push(frame);
+            }
+            prop_assert_eq!(read, messages);
+        }
+    }
+}
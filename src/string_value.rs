@@ -0,0 +1,160 @@
+use crate::escape::{self, Quote};
+use anyhow::{anyhow, Context, Result};
+use bstr::{BString, ByteSlice};
+use std::ops::Range;
+use tree_sitter::Node;
+use yfnutool_macros::nu_kind_sym;
+
+// The value of a string literal in the command line
+#[derive(Debug, PartialEq)]
+pub struct StringValue {
+    pub quote: Quote,
+    pub value: BString,
+    // Byte offset into the command line that each byte of the value came from
+    pub source_offsets: Vec<usize>,
+    // The whole literal, delimiters included
+    pub range: Range<usize>,
+    // Everything between the delimiters
+    pub content_range: Range<usize>,
+}
+
+impl StringValue {
+    // Index into the value of the first byte at or after `source_offset`
+    pub fn value_offset(&self, source_offset: usize) -> usize {
+        self.source_offsets
+            .partition_point(|offset| *offset < source_offset)
+    }
+}
+
+// Takes a val_string or val_interpolated node
+pub fn evaluate(node: Node, source: &[u8]) -> Result<StringValue> {
+    match node.kind_id() {
+        nu_kind_sym!("val_string") | nu_kind_sym!("val_interpolated") => {
+            evaluate_literal(source, node.byte_range())
+        }
+        _ => Err(anyhow!("{} node is not a string", node.kind())),
+    }
+}
+
+// Evaluates the string literal or bare word spanning `range`
+pub fn evaluate_literal(source: &[u8], range: Range<usize>) -> Result<StringValue> {
+    // Nodes of a recovered tree can run on into closers that aren't in the command line
+    let text = source.get(range.clone()).with_context(|| {
+        format!(
+            "String at {:?} runs past the end of the command line",
+            range
+        )
+    })?;
+    let quote = match Quote::of_literal(text) {
+        Some(quote) => quote,
+        None if escape::escape(Quote::Bare, text).is_some() => Quote::Bare,
+        None => {
+            return Err(anyhow!(
+                "{:?} is not a finished string literal",
+                text.as_bstr()
+            ))
+        }
+    };
+    let (opening, closing) = quote.delimiters();
+    let content_range = range.start + opening.len()..range.end - closing.len();
+    let unescaped = escape::unescape(quote, &source[content_range.clone()])
+        .with_context(|| format!("Evaluating {:?} string at byte {}", quote, range.start))?;
+    Ok(StringValue {
+        quote,
+        value: unescaped.value.into(),
+        source_offsets: unescaped
+            .source_offsets
+            .into_iter()
+            .map(|offset| offset + content_range.start)
+            .collect(),
+        range,
+        content_range,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd_line::{self, str_repr, Buffer};
+    use yare::parameterized;
+
+    #[parameterized(
+        single = {"echo 'a\\b'", 5..10, Quote::Single, "a\\b", vec![6, 7, 8]},
+        double = {r#"echo "a\nb""#, 5..11, Quote::Double, "a\nb", vec![6, 7, 9]},
+        raw = {"r#'a'b'#", 0..8, Quote::Raw { hashes: 1 }, "a'b", vec![3, 4, 5]},
+        interpolated = {r#"$"\(a('b'))""#, 0..12, Quote::DoubleInterpolated, "(ab)", vec![2, 4, 5, 10]},
+        bare = {"ls src/", 3..7, Quote::Bare, "src/", vec![3, 4, 5, 6]},
+        unicode_escape = {r#""\u{E9}""#, 0..8, Quote::Double, "é", vec![1, 1]},
+    )]
+    fn should_evaluate(
+        source: &str,
+        range: Range<usize>,
+        quote: Quote,
+        value: &str,
+        source_offsets: Vec<usize>,
+    ) {
+        let result = evaluate_literal(source.as_bytes(), range).unwrap();
+        pretty_assertions::assert_eq!(result.quote, quote);
+        pretty_assertions::assert_eq!(result.value, value);
+        pretty_assertions::assert_eq!(result.source_offsets, source_offsets);
+    }
+
+    #[parameterized(
+        invalid_escape = {r#""\q""#},
+        unfinished = {r#""abc"#},
+        real_interpolation = {r#"$"($a)""#},
+        not_a_string = {"a b"},
+    )]
+    fn should_fail(source: &str) {
+        assert!(evaluate_literal(source.as_bytes(), 0..source.len()).is_err());
+    }
+
+    #[test]
+    fn should_fail_past_end() {
+        assert!(evaluate_literal(b"echo 'ab", 5..9).is_err());
+    }
+
+    #[test]
+    fn should_map_source_offsets_to_value() {
+        let result = evaluate_literal(br#""a\"b""#, 0..6).unwrap();
+        pretty_assertions::assert_eq!(result.value_offset(2), 1);
+        pretty_assertions::assert_eq!(result.value_offset(3), 2);
+        pretty_assertions::assert_eq!(result.value_offset(4), 2);
+        pretty_assertions::assert_eq!(result.value_offset(5), 3);
+    }
+
+    #[parameterized(
+        double = {str_repr(r#"echo "a\t|b""#), "a\tb"},
+        raw = {str_repr("echo r#'a|b'#"), "ab"},
+        interpolated = {str_repr("echo $'a(|)b'"), "ab"},
+    )]
+    fn should_evaluate_node_at_cursor(input: cmd_line::Rope, value: &str) {
+        let mut input = crate::syntax::Parsed::new(input);
+        let recovered = input
+            .recovered_tree(&mut crate::nu_parser().unwrap())
+            .unwrap();
+        let string_node = crate::context::classify(&recovered, &input)
+            .unwrap()
+            .string
+            .unwrap()
+            .node;
+        pretty_assertions::assert_eq!(
+            evaluate(string_node, &input.contents()).unwrap().value,
+            value
+        );
+    }
+
+    #[test]
+    fn should_fail_on_node_running_into_closers() {
+        let mut input = crate::syntax::Parsed::new(str_repr::<_, cmd_line::Rope>("echo 'ab|"));
+        let recovered = input
+            .recovered_tree(&mut crate::nu_parser().unwrap())
+            .unwrap();
+        let string_node = crate::context::classify(&recovered, &input)
+            .unwrap()
+            .string
+            .unwrap()
+            .node;
+        assert!(evaluate(string_node, &input.contents()).is_err());
+    }
+}
@@ -0,0 +1,469 @@
+use crate::cmd_line::{self, AnchorName, Buffer, Gravity};
+use anyhow::{Context, Result};
+use bstr::{BString, ByteSlice};
+use log::debug;
+use std::borrow::Cow;
+use std::ops::Range;
+use tree_sitter::{InputEdit, Node, Point, Tree};
+
+// A command line that keeps its syntax tree in step with every edit, so asking for the tree again
+// only reparses what changed since last time
+#[derive(Clone, Debug)]
+pub struct Parsed<B> {
+    pub buffer: B,
+    tree: Option<Tree>,
+    // Whether the tree was parsed from the buffer as it is now, rather than just edited to match
+    fresh: bool,
+}
+
+// What's left of a command line once it's been handled, for parsing the next one incrementally
+pub struct Previous {
+    text: BString,
+    tree: Tree,
+}
+
+// A tree for a command line that's likely still being typed, parsed as if it ended with whatever
+// closers leave the fewest errors. Nodes can run on into the closers, which aren't in the buffer,
+// so their ranges should come from `range`.
+pub struct Recovered {
+    pub tree: Tree,
+    len: usize,
+}
+
+impl Recovered {
+    // The part of `node` that's in the buffer
+    pub fn range(&self, node: Node) -> Range<usize> {
+        node.start_byte().min(self.len)..node.end_byte().min(self.len)
+    }
+}
+
+impl<B: Buffer> Parsed<B> {
+    pub fn new(buffer: B) -> Self {
+        Parsed {
+            buffer,
+            tree: None,
+            fresh: false,
+        }
+    }
+
+    // Starts from the tree of an earlier command line, treating whatever differs between the two
+    // as a single edit
+    pub fn reusing(buffer: B, previous: Option<Previous>) -> Self {
+        let Some(Previous { text, mut tree }) = previous else {
+            return Self::new(buffer);
+        };
+        let edit = diff(&text, &buffer.contents());
+        tree.edit(&input_edit(
+            &cmd_line::Bytes::new(text, 0),
+            edit.range,
+            &edit.replacement,
+        ));
+        Parsed {
+            buffer,
+            tree: Some(tree),
+            fresh: false,
+        }
+    }
+
+    pub fn tree(&mut self, parser: &mut tree_sitter::Parser) -> Result<Tree> {
+        if !self.fresh {
+            self.tree = Some(crate::parse_cli(parser, &self.buffer, self.tree.as_ref())?);
+            self.fresh = true;
+        }
+        Ok(self.tree.clone().expect("Just parsed"))
+    }
+
+    // Leaves the tree as it is if it parses without errors
+    pub fn recovered_tree(&mut self, parser: &mut tree_sitter::Parser) -> Result<Recovered> {
+        let tree = self.tree(parser)?;
+        let text = self.buffer.contents();
+        let mut best = (error_count(&tree), BString::default(), tree.clone());
+        for closers in candidate_closers(&text) {
+            if best.0 == 0 {
+                break;
+            }
+            let mut old_tree = tree.clone();
+            old_tree.edit(&input_edit(&self.buffer, text.len()..text.len(), &closers));
+            let candidate = parser
+                .parse([text.as_ref(), &closers].concat(), Some(&old_tree))
+                .with_context(|| "Tree-sitter unable to parse tree from input")?;
+            let errors = error_count(&candidate);
+            if errors < best.0 {
+                best = (errors, closers, candidate);
+            }
+        }
+        let (errors, closers, tree) = best;
+        if !closers.is_empty() {
+            debug!(
+                "Parsed as if ending with {:?}, leaving {} errors",
+                closers, errors
+            );
+        }
+        Ok(Recovered {
+            tree,
+            len: text.len(),
+        })
+    }
+
+    // Switches backend without losing the tree
+    pub fn try_map<C>(self, f: impl FnOnce(B) -> Result<C>) -> Result<Parsed<C>> {
+        Ok(Parsed {
+            buffer: f(self.buffer)?,
+            tree: self.tree,
+            fresh: self.fresh,
+        })
+    }
+
+    pub fn previous(&self) -> Option<Previous> {
+        Some(Previous {
+            text: self.buffer.contents().into_owned().into(),
+            tree: self.tree.clone()?,
+        })
+    }
+}
+
+impl<B: Buffer> Buffer for Parsed<B> {
+    fn len(&self) -> usize {
+        self.buffer.len()
+    }
+    fn byte(&self, pos: usize) -> u8 {
+        self.buffer.byte(pos)
+    }
+    fn slice(&self, range: Range<usize>) -> Cow<'_, [u8]> {
+        self.buffer.slice(range)
+    }
+    fn chunk_at(&self, pos: usize) -> &[u8] {
+        self.buffer.chunk_at(pos)
+    }
+    fn line_start(&self, pos: usize) -> (usize, usize) {
+        self.buffer.line_start(pos)
+    }
+    fn cursor_pos(&self) -> usize {
+        self.buffer.cursor_pos()
+    }
+    fn set_cursor_pos(&mut self, pos: usize) {
+        self.buffer.set_cursor_pos(pos);
+    }
+    fn replace(&mut self, range: Range<usize>, replacement: &[u8], cursor_gravity: Gravity) {
+        if let Some(tree) = &mut self.tree {
+            tree.edit(&input_edit(&self.buffer, range.clone(), replacement));
+        }
+        self.fresh = false;
+        self.buffer.replace(range, replacement, cursor_gravity);
+    }
+    fn anchor(&self, name: AnchorName) -> Option<usize> {
+        self.buffer.anchor(name)
+    }
+    fn set_anchor(&mut self, name: AnchorName, pos: usize, gravity: Gravity) {
+        self.buffer.set_anchor(name, pos, gravity);
+    }
+}
+
+fn error_count(tree: &Tree) -> usize {
+    let mut count = 0;
+    let mut cursor = tree.walk();
+    'walk: loop {
+        let node = cursor.node();
+        if node.is_error() || node.is_missing() {
+            count += 1;
+        }
+        if cursor.goto_first_child() || cursor.goto_next_sibling() {
+            continue;
+        }
+        while cursor.goto_parent() {
+            if cursor.goto_next_sibling() {
+                continue 'walk;
+            }
+        }
+        return count;
+    }
+}
+
+// Whatever would close everything still open at the end of `text`, innermost first, followed by
+// each closer on its own in case the scan guessed wrong
+fn candidate_closers(text: &[u8]) -> Vec<BString> {
+    let mut open: Vec<BString> = Vec::new();
+    let mut idx = 0;
+    while idx < text.len() {
+        let in_string = open
+            .last()
+            .filter(|closer| matches!(closer[0], b'\'' | b'"' | b'`'));
+        match (in_string, text[idx]) {
+            (Some(closer), _) if text[idx..].starts_with(closer) => {
+                idx += closer.len();
+                open.pop();
+                continue;
+            }
+            (Some(closer), b'\\') if closer.as_slice() == b"\"" => idx += 1,
+            (Some(_), _) => (),
+            (None, b'(') => open.push(")".into()),
+            (None, b'[') => open.push("]".into()),
+            (None, b'{') => open.push("}".into()),
+            (None, b'"' | b'`') => open.push([text[idx]].into()),
+            (None, b'\'') => {
+                // Raw strings close with as many hashes as they open with
+                let hashes = text[..idx]
+                    .iter()
+                    .rev()
+                    .take_while(|char| **char == b'#')
+                    .count();
+                let raw = hashes > 0 && text[..idx - hashes].ends_with(b"r");
+                let hashes = if raw { hashes } else { 0 };
+                open.push([&b"'"[..], &b"#".repeat(hashes)].concat().into());
+            }
+            (None, closer @ (b')' | b']' | b'}')) => {
+                if open.last().is_some_and(|open| open.as_slice() == [closer]) {
+                    open.pop();
+                }
+            }
+            (None, b'#') if idx == 0 || text[idx - 1].is_ascii_whitespace() => {
+                idx += text[idx..].find_byte(b'\n').unwrap_or(text.len() - idx);
+            }
+            (None, _) => (),
+        }
+        idx += 1;
+    }
+    let mut candidates = Vec::new();
+    if !open.is_empty() {
+        candidates.push(
+            open.iter()
+                .rev()
+                .flat_map(|closer| closer.iter())
+                .copied()
+                .collect(),
+        );
+    }
+    for closer in ["'", "\"", "`", ")", "]", "}"] {
+        let closer = BString::from(closer);
+        if !candidates.contains(&closer) {
+            candidates.push(closer);
+        }
+    }
+    candidates
+}
+
+// The single edit that turns `before` into `after`, covering everything between what they start
+// and end with in common
+fn diff(before: &[u8], after: &[u8]) -> cmd_line::Edit {
+    let prefix = common_len(before.iter(), after.iter());
+    let suffix = common_len(before[prefix..].iter().rev(), after[prefix..].iter().rev());
+    cmd_line::Edit {
+        range: prefix..before.len() - suffix,
+        replacement: after[prefix..after.len() - suffix].into(),
+    }
+}
+
+fn common_len<'a>(
+    left: impl Iterator<Item = &'a u8>,
+    right: impl Iterator<Item = &'a u8>,
+) -> usize {
+    left.zip(right)
+        .take_while(|(left, right)| left == right)
+        .count()
+}
+
+// Describes replacing `range` of `buffer` for Tree::edit. Only the edited range and the start of
+// its line get looked at, so edits cost the same however long the command line is.
+fn input_edit(buffer: &impl Buffer, range: Range<usize>, replacement: &[u8]) -> InputEdit {
+    let (row, line_start) = buffer.line_start(range.start);
+    let start_position = Point {
+        row,
+        column: range.start - line_start,
+    };
+    InputEdit {
+        start_byte: range.start,
+        old_end_byte: range.end,
+        new_end_byte: range.start + replacement.len(),
+        start_position,
+        old_end_position: advance(start_position, &buffer.slice(range)),
+        new_end_position: advance(start_position, replacement),
+    }
+}
+
+// Where `text` ends if it starts at `point`. Tree-sitter only breaks rows at `\n` and counts
+// columns in bytes.
+fn advance(point: Point, text: &[u8]) -> Point {
+    match text.rfind_byte(b'\n') {
+        Some(newline) => Point {
+            row: point.row + text.find_iter("\n").count(),
+            column: text.len() - newline - 1,
+        },
+        None => Point {
+            row: point.row,
+            column: point.column + text.len(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd_line::{str_repr, Bytes};
+    use proptest::prelude::*;
+    use yare::parameterized;
+
+    // Every node, with where it is, so that trees only match if they'd answer queries alike
+    fn nodes(tree: &Tree) -> Vec<(&'static str, Range<usize>, Point, Point)> {
+        let mut result = Vec::new();
+        let mut cursor = tree.walk();
+        'walk: loop {
+            let node = cursor.node();
+            result.push((
+                node.kind(),
+                node.byte_range(),
+                node.start_position(),
+                node.end_position(),
+            ));
+            if cursor.goto_first_child() || cursor.goto_next_sibling() {
+                continue;
+            }
+            while cursor.goto_parent() {
+                if cursor.goto_next_sibling() {
+                    continue 'walk;
+                }
+            }
+            return result;
+        }
+    }
+
+    fn from_scratch(buffer: &Bytes) -> Tree {
+        crate::parse_cli(&mut crate::nu_parser().unwrap(), buffer, None).unwrap()
+    }
+
+    fn any_text() -> impl Strategy<Value = String> {
+        r#"[a-z0-9 "'()\[\]{}$,:\r\n]{0,16}"#
+    }
+
+    fn any_edit(len: usize) -> impl Strategy<Value = (Range<usize>, String)> {
+        (0..len + 1, 0..len + 1, any_text())
+            .prop_map(|(start, end, replacement)| (start.min(end)..start.max(end), replacement))
+    }
+
+    proptest! {
+        #[test]
+        fn should_reparse_like_from_scratch(
+            (text, edits) in any_text().prop_flat_map(|text| {
+                let len = text.len();
+                (Just(text), proptest::collection::vec(any_edit(len), 1..4))
+            })
+        ) {
+            let mut parser = crate::nu_parser().unwrap();
+            let mut parsed = Parsed::new(str_repr::<_, Bytes>(text + "|"));
+            parsed.tree(&mut parser).unwrap();
+            for (range, replacement) in edits {
+                let range = range.start.min(parsed.len())..range.end.min(parsed.len());
+                parsed.overwrite_range(range, replacement.as_bytes());
+            }
+            prop_assert_eq!(
+                nodes(&parsed.tree(&mut parser).unwrap()),
+                nodes(&from_scratch(&parsed.buffer))
+            );
+        }
+
+        #[test]
+        fn should_reuse_previous_tree(before in any_text(), after in any_text()) {
+            let mut parser = crate::nu_parser().unwrap();
+            let mut previous = Parsed::new(str_repr::<_, Bytes>(before + "|"));
+            previous.tree(&mut parser).unwrap();
+            let after: Bytes = str_repr(after + "|");
+            let mut parsed = Parsed::reusing(after.clone(), previous.previous());
+            prop_assert_eq!(
+                nodes(&parsed.tree(&mut parser).unwrap()),
+                nodes(&from_scratch(&after))
+            );
+        }
+    }
+
+    // The recovered tree is the one the closed command line parses to
+    #[parameterized(
+        finished = {"ls | get name", ""},
+        subexpression = {"echo (ls | get name", ")"},
+        nested_lists = {"[1 [2", "]]"},
+        string = {r#"echo "foo"#, r#"""#},
+        string_in_closure = {"each { print 'a", "'}"},
+    )]
+    fn should_recover(text: &str, closers: &str) {
+        let mut parser = crate::nu_parser().unwrap();
+        let mut parsed = Parsed::new(str_repr::<_, Bytes>(text.to_string() + "|"));
+        let recovered = parsed.recovered_tree(&mut parser).unwrap();
+        let closed = from_scratch(&str_repr(format!("{}{}|", text, closers)));
+        pretty_assertions::assert_eq!(
+            (
+                nodes(&recovered.tree),
+                error_count(&recovered.tree),
+                recovered.range(recovered.tree.root_node())
+            ),
+            (nodes(&closed), 0, 0..text.len())
+        );
+    }
+
+    #[parameterized(
+        nothing_open = {"foo (bar) [baz]", &[]},
+        nested = {"foo (bar [baz {", &["}])"]},
+        double_quote = {r#"foo ("ba\"r"#, &[r#"")"#]},
+        parens_in_string = {"foo 'ba(r", &["'"]},
+        raw_string = {"foo r##'bar'#", &["'##"]},
+        comment = {"foo (bar # (", &[")"]},
+        hash_in_word = {"foo (ba#r", &[")"]},
+        stray_closer = {"foo ) (bar", &[")"]},
+    )]
+    fn should_guess_closers(text: &str, guessed: &[&str]) {
+        pretty_assertions::assert_eq!(
+            &candidate_closers(text.as_bytes())[..guessed.len()],
+            guessed
+                .iter()
+                .map(|closers| BString::from(*closers))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[parameterized(
+        insertion = {"abc", "abXc", 2..2, "X"},
+        deletion = {"abXc", "abc", 2..3, ""},
+        repeated = {"aaa", "aa", 2..3, ""},
+        everything = {"abc", "xyz", 0..3, "xyz"},
+        nothing = {"abc", "abc", 3..3, ""},
+    )]
+    fn should_diff(before: &str, after: &str, range: Range<usize>, replacement: &str) {
+        pretty_assertions::assert_eq!(
+            diff(before.as_bytes(), after.as_bytes()),
+            cmd_line::Edit {
+                range,
+                replacement: replacement.into()
+            }
+        );
+    }
+
+    #[parameterized(
+        same_line = {"ab\ncd", 3..4, "xy", (1, 0), (1, 1), (1, 2)},
+        adds_line = {"ab\ncd", 4..5, "x\ny", (1, 1), (1, 2), (2, 1)},
+        joins_lines = {"ab\ncd", 1..4, "", (0, 1), (1, 1), (0, 1)},
+        inside_crlf = {"a\r\nb", 2..2, "x", (0, 2), (0, 2), (0, 3)},
+        after_crlf = {"a\r\nb", 3..4, "", (1, 0), (1, 1), (1, 0)},
+    )]
+    fn should_describe_edit(
+        text: &str,
+        range: Range<usize>,
+        replacement: &str,
+        start: (usize, usize),
+        old_end: (usize, usize),
+        new_end: (usize, usize),
+    ) {
+        let bytes = cmd_line::Bytes::new(text, 0);
+        let rope = cmd_line::Rope::try_from(bytes.clone()).unwrap();
+        let point = |(row, column)| Point { row, column };
+        for edit in [
+            input_edit(&bytes, range.clone(), replacement.as_bytes()),
+            input_edit(&rope, range.clone(), replacement.as_bytes()),
+        ] {
+            pretty_assertions::assert_eq!(
+                (
+                    edit.start_position,
+                    edit.old_end_position,
+                    edit.new_end_position
+                ),
+                (point(start), point(old_end), point(new_end))
+            );
+        }
+    }
+}
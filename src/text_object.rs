@@ -0,0 +1,287 @@
+use crate::cmd_line::{Buffer, Gravity};
+use crate::context::{self, Role, StringAt};
+use crate::syntax::{Parsed, Recovered};
+use anyhow::Result;
+use bstr::BString;
+use clap::ValueEnum;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+use tree_sitter::Node;
+use yfnutool_macros::nu_kind_sym;
+
+// A piece of syntax around the cursor to act on, named the way vim names its text objects:
+// `inner-string`, `around-parens` and so on
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TextObject {
+    pub extent: Extent,
+    pub kind: Kind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Extent {
+    // Leaving out delimiters, or for arguments and pipeline elements, what separates them
+    Inner,
+    Around,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Kind {
+    String,
+    Parens,
+    Brackets,
+    Braces,
+    Closure,
+    // Flags count as arguments too
+    Argument,
+    PipeElement,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Delete,
+    // Takes out the same text as Delete. Going on to insert is up to the editor.
+    Change,
+    // Leaves the text alone, moving the cursor to the start of the object the way vim does
+    Yank,
+}
+
+impl FromStr for TextObject {
+    type Err = String;
+    fn from_str(name: &str) -> Result<Self, String> {
+        let (extent, kind) = name
+            .split_once('-')
+            .ok_or_else(|| format!("Text object {:?} isn't inner-… or around-…", name))?;
+        Ok(TextObject {
+            extent: Extent::from_str(extent, false)?,
+            kind: Kind::from_str(kind, false)?,
+        })
+    }
+}
+impl fmt::Display for TextObject {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = |value: Option<clap::builder::PossibleValue>| {
+            value
+                .expect("No variants are skipped")
+                .get_name()
+                .to_string()
+        };
+        write!(
+            fmt,
+            "{}-{}",
+            name(self.extent.to_possible_value()),
+            name(self.kind.to_possible_value())
+        )
+    }
+}
+impl TryFrom<String> for TextObject {
+    type Error = String;
+    fn try_from(name: String) -> Result<Self, String> {
+        name.parse()
+    }
+}
+impl From<TextObject> for String {
+    fn from(object: TextObject) -> Self {
+        object.to_string()
+    }
+}
+
+// Hands back the text the object covered too, or None if there's no such object at the cursor
+pub fn act<B: Buffer>(
+    parser: &mut tree_sitter::Parser,
+    mut input: Parsed<B>,
+    action: Action,
+    object: TextObject,
+) -> Result<(Parsed<B>, Option<BString>)> {
+    let recovered = input.recovered_tree(parser)?;
+    let Some(range) = range(&recovered, &input, object)? else {
+        debug!("No {} at the cursor", object);
+        return Ok((input, None));
+    };
+    debug!("{:?} {} at {:?}", action, object, range);
+    let taken = BString::from(input.slice(range.clone()).into_owned());
+    if action != Action::Yank {
+        input.replace(range.clone(), b"", Gravity::Left);
+    }
+    input.set_cursor_pos(range.start);
+    Ok((input, Some(taken)))
+}
+
+fn range(
+    recovered: &Recovered,
+    input: &impl Buffer,
+    object: TextObject,
+) -> Result<Option<Range<usize>>> {
+    let context = context::classify(recovered, input)?;
+    let extent = object.extent;
+    Ok(match object.kind {
+        Kind::String => context
+            .string
+            .map(|string| string_range(recovered, input, string, extent)),
+        Kind::Parens => bracketed(recovered, context.node, ("(", ")"), extent),
+        Kind::Brackets => bracketed(recovered, context.node, ("[", "]"), extent),
+        Kind::Braces => bracketed(recovered, context.node, ("{", "}"), extent),
+        Kind::Closure => closure(recovered, context.node, extent),
+        Kind::Argument => match context.role {
+            Role::Argument { argument, .. } => Some(separated(recovered, argument, extent)),
+            Role::Flag(flag) => Some(separated(recovered, flag, extent)),
+            _ => None,
+        },
+        Kind::PipeElement => context
+            .pipe_element
+            .map(|pipe_element| separated(recovered, pipe_element, extent)),
+    })
+}
+
+// An unfinished string has no closing delimiter to leave out
+fn string_range(
+    recovered: &Recovered,
+    input: &impl Buffer,
+    string: StringAt,
+    extent: Extent,
+) -> Range<usize> {
+    let range = recovered.range(string.node);
+    if extent == Extent::Around {
+        return range;
+    }
+    let (opening, closing) = string.quote.delimiters();
+    let text = input.slice(range.clone());
+    let start = range.start + opening.len().min(range.len());
+    let has_closing = text.len() >= opening.len() + closing.len() && text.ends_with(&closing);
+    let end = if has_closing {
+        range.end - closing.len()
+    } else {
+        range.end
+    };
+    start..end
+}
+
+// The innermost node around `node` that starts with `open` and ends with `close`
+fn bracketed(
+    recovered: &Recovered,
+    node: Node,
+    (open, close): (&str, &str),
+    extent: Extent,
+) -> Option<Range<usize>> {
+    context::ancestors(node).find_map(|node| {
+        let first = node.child(0).filter(|first| first.kind() == open)?;
+        let last = node
+            .child(node.child_count().checked_sub(1)?)
+            .filter(|last| last.kind() == close && *last != first)?;
+        let (first, last) = (recovered.range(first), recovered.range(last));
+        Some(match extent {
+            Extent::Inner => first.end..last.start,
+            Extent::Around => first.start..last.end,
+        })
+    })
+}
+
+// The inside of a closure is its body, after any parameters
+fn closure(recovered: &Recovered, node: Node, extent: Extent) -> Option<Range<usize>> {
+    let closure =
+        context::ancestors(node).find(|node| node.kind_id() == nu_kind_sym!("val_closure"))?;
+    let range = recovered.range(closure);
+    if extent == Extent::Around {
+        return Some(range);
+    }
+    let mut walk = closure.walk();
+    let children: Vec<Node> = closure.children(&mut walk).collect();
+    let start = children
+        .iter()
+        .find(|child| child.kind_id() == nu_kind_sym!("parameter_pipes"))
+        .or(children.first())
+        .map_or(range.start, |child| recovered.range(*child).end);
+    let end = children
+        .last()
+        .filter(|child| child.kind() == "}")
+        .map_or(range.end, |child| recovered.range(*child).start);
+    Some(start..end.max(start))
+}
+
+// Around takes in the gap up to the next node alongside, or failing that the gap back from the
+// previous one, so that whatever's left stays separated
+fn separated(recovered: &Recovered, node: Node, extent: Extent) -> Range<usize> {
+    let range = recovered.range(node);
+    if extent == Extent::Inner {
+        return range;
+    }
+    if let Some(next) = node.next_named_sibling() {
+        return range.start..recovered.range(next).start;
+    }
+    if let Some(previous) = node.prev_named_sibling() {
+        return recovered.range(previous).end..range.end;
+    }
+    range
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd_line::{str_repr, Rope};
+    use crate::Operation;
+    use yare::parameterized;
+
+    #[parameterized(
+        inner_string = {Operation::Delete, "inner-string", "echo 'fo|o'", "echo '|'", "foo"},
+        around_string = {Operation::Delete, "around-string", "echo 'fo|o' bar", "echo | bar", "'foo'"},
+        inner_interpolated_string = {Operation::Change, "inner-string", r#"echo $"a(1)|b""#, r#"echo $"|""#, "a(1)b"},
+        inner_unfinished_string = {Operation::Delete, "inner-string", "echo 'fo|", "echo '|", "fo"},
+        inner_raw_string = {Operation::Delete, "inner-string", "echo r#'f|oo'#", "echo r#'|'#", "foo"},
+        inner_parens = {Operation::Delete, "inner-parens", "echo (ls | len|gth)", "echo (|)", "ls | length"},
+        around_brackets = {Operation::Delete, "around-brackets", "[1, |2]", "|", "[1, 2]"},
+        nested_brackets = {Operation::Delete, "inner-brackets", "[1, [|2]]", "[1, [|]]", "2"},
+        inner_braces = {Operation::Change, "inner-braces", "{a: |1}", "{|}", "a: 1"},
+        inner_closure = {Operation::Delete, "inner-closure", "each { print |$in }", "each {|}", " print $in "},
+        around_closure = {Operation::Delete, "around-closure", "each { pr|int }", "each |", "{ print }"},
+        inner_argument = {Operation::Delete, "inner-argument", "cp fo|o bar", "cp | bar", "foo"},
+        around_argument = {Operation::Delete, "around-argument", "cp fo|o bar", "cp |bar", "foo "},
+        around_last_argument = {Operation::Delete, "around-argument", "cp foo ba|r", "cp foo|", " bar"},
+        around_flag = {Operation::Delete, "around-argument", "ls --a|ll foo", "ls |foo", "--all "},
+        inner_pipe_element = {Operation::Delete, "inner-pipe-element", "ls | whe|re size > 1kb", "ls | |", "where size > 1kb"},
+        around_pipe_element = {Operation::Delete, "around-pipe-element", "l|s | get name", "|get name", "ls | "},
+        yank = {Operation::Yank, "inner-string", "echo 'fo|o'", "echo '|foo'", "foo"},
+    )]
+    fn should_act_on(operation: Operation, object: &str, before: &str, after: &str, taken: &str) {
+        pretty_assertions::assert_eq!(
+            crate::on_every_backend_with(
+                operation,
+                Some(object.parse().unwrap()),
+                str_repr(before)
+            ),
+            (str_repr::<_, Rope>(after), Some(BString::from(taken)))
+        );
+    }
+
+    #[parameterized(
+        not_a_string = {"inner-string", "echo 1|"},
+        not_in_parens = {"around-parens", "echo f|oo"},
+        not_an_argument = {"inner-argument", "e|cho foo"},
+    )]
+    fn should_leave_alone_without_object(object: &str, before: &str) {
+        pretty_assertions::assert_eq!(
+            crate::on_every_backend_with(
+                Operation::Delete,
+                Some(object.parse().unwrap()),
+                str_repr(before)
+            ),
+            (str_repr::<_, Rope>(before), None)
+        );
+    }
+
+    #[parameterized(
+        inner = {"inner-pipe-element", Ok(TextObject { extent: Extent::Inner, kind: Kind::PipeElement })},
+        around = {"around-parens", Ok(TextObject { extent: Extent::Around, kind: Kind::Parens })},
+        no_extent = {"string", Err(())},
+        unknown_kind = {"inner-word", Err(())},
+    )]
+    fn should_parse(name: &str, expected: Result<TextObject, ()>) {
+        let parsed = name.parse::<TextObject>();
+        pretty_assertions::assert_eq!(parsed.clone().map_err(|_| ()), expected);
+        if let Ok(object) = parsed {
+            pretty_assertions::assert_eq!(object.to_string(), name);
+        }
+    }
+}